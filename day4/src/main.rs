@@ -1,4 +1,4 @@
-use std::{cmp, iter};
+use std::{cmp, time::Instant};
 
 fn main() {
     let input = include_str!("../puzzle_input.txt");
@@ -15,17 +15,80 @@ fn main() {
 .@@@@@@@@.
 @.@.@@@.@."#;
 
-    let mut grid = create_grid(input);
-    let total_moved = remove_rolls(&mut grid);
+    let grid = create_grid(input);
+    // find_peel_depths(&grid, &Rule::default()).print();
+    // benchmark(&grid, &Rule::default(), 20);
+    // let rule = Rule::new(Neighbourhood::VonNeumann, Comparison::Less, 2, true);
+    let rule = Rule::default();
+    let mut grid = BitGrid::from(grid.as_slice());
+    let total_moved = remove_rolls(&mut grid, &rule);
     println!("Total moved: {total_moved}");
 }
 
 #[allow(dead_code)]
-fn count_accessible_rolls(grid: &[Vec<bool>]) -> usize {
+fn count_accessible_rolls(grid: &BitGrid, rule: &Rule) -> usize {
+    (0..grid.rows)
+        .map(|i| {
+            grid.removable_in_row(i, rule)
+                .iter()
+                .map(|w| w.count_ones() as usize)
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+fn remove_rolls(grid: &mut BitGrid, rule: &Rule) -> usize {
+    let mut count = 0;
+    remove_rolls_by_round(grid, rule, |_, removed| count += removed.len());
+    count
+}
+
+/// Repeatedly removes every roll the rule allows, one round at a time,
+/// calling `on_round` with the (1-based) round number and the cells
+/// removed in that round. Returns the number of rounds until stable.
+fn remove_rolls_by_round(
+    grid: &mut BitGrid,
+    rule: &Rule,
+    mut on_round: impl FnMut(usize, &[(usize, usize)]),
+) -> usize {
+    let mut round = 0;
+    let mut masks = Vec::with_capacity(grid.rows);
+    let mut to_remove = Vec::new();
+    loop {
+        // Find everything to remove before removing anything,
+        // so removals in this round don't affect each other
+        masks.clear();
+        to_remove.clear();
+        for i in 0..grid.rows {
+            let mask = grid.removable_in_row(i, rule);
+            for (w, &word) in mask.iter().enumerate() {
+                let mut word = word;
+                while word != 0 {
+                    to_remove.push((i, w * 64 + word.trailing_zeros() as usize));
+                    word &= word - 1;
+                }
+            }
+            masks.push(mask);
+        }
+
+        if to_remove.is_empty() {
+            return round;
+        } else {
+            round += 1;
+            on_round(round, &to_remove);
+        }
+        for (i, mask) in masks.iter().enumerate() {
+            grid.remove_from_row(i, mask);
+        }
+    }
+}
+
+#[allow(dead_code)]
+fn count_accessible_rolls_naive(grid: &[Vec<bool>], rule: &Rule) -> usize {
     let mut count = 0;
     for i in 0..grid.len() {
         for j in 0..grid[0].len() {
-            if grid[i][j] && get_surrounding_cells(grid, i, j).filter(|b| **b).count() < 4 {
+            if grid[i][j] && rule.is_removable(grid, i, j) {
                 count += 1;
             }
         }
@@ -33,14 +96,15 @@ fn count_accessible_rolls(grid: &[Vec<bool>]) -> usize {
     count
 }
 
-fn remove_rolls(grid: &mut [Vec<bool>]) -> usize {
+#[allow(dead_code)]
+fn remove_rolls_naive(grid: &mut [Vec<bool>], rule: &Rule) -> usize {
     let mut count = 0;
     let mut to_remove = Vec::with_capacity(grid.len() * grid[0].len());
     loop {
         to_remove.clear();
         for i in 0..grid.len() {
             for j in 0..grid[0].len() {
-                if grid[i][j] && get_surrounding_cells(grid, i, j).filter(|b| **b).count() < 4 {
+                if grid[i][j] && rule.is_removable(grid, i, j) {
                     to_remove.push((i, j));
                 }
             }
@@ -57,6 +121,122 @@ fn remove_rolls(grid: &mut [Vec<bool>]) -> usize {
     }
 }
 
+#[allow(dead_code)]
+fn benchmark(grid: &[Vec<bool>], rule: &Rule, iterations: u32) {
+    let bits = BitGrid::from(grid);
+
+    let start = Instant::now();
+    let mut naive_accessible = 0;
+    for _ in 0..iterations {
+        naive_accessible = count_accessible_rolls_naive(grid, rule);
+    }
+    let naive_count_time = start.elapsed() / iterations;
+
+    let start = Instant::now();
+    let mut accessible = 0;
+    for _ in 0..iterations {
+        accessible = count_accessible_rolls(&bits, rule);
+    }
+    let count_time = start.elapsed() / iterations;
+    assert_eq!(naive_accessible, accessible, "Accessible counts differ");
+
+    let start = Instant::now();
+    let mut naive_removed = 0;
+    for _ in 0..iterations {
+        naive_removed = remove_rolls_naive(&mut grid.to_vec(), rule);
+    }
+    let naive_remove_time = start.elapsed() / iterations;
+
+    let start = Instant::now();
+    let mut removed = 0;
+    for _ in 0..iterations {
+        removed = remove_rolls(&mut bits.clone(), rule);
+    }
+    let remove_time = start.elapsed() / iterations;
+    assert_eq!(naive_removed, removed, "Removed counts differ");
+
+    println!("count_accessible_rolls: naive {naive_count_time:?}, bits {count_time:?}");
+    println!("remove_rolls: naive {naive_remove_time:?}, bits {remove_time:?}");
+}
+
+#[allow(dead_code)]
+fn find_peel_depths(grid: &[Vec<bool>], rule: &Rule) -> PeelDepths {
+    let mut depths: Vec<Vec<Depth>> = grid
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|&b| if b { Depth::Survived } else { Depth::Empty })
+                .collect()
+        })
+        .collect();
+    let mut histogram = Vec::new();
+
+    let mut grid = BitGrid::from(grid);
+    let max_depth = remove_rolls_by_round(&mut grid, rule, |round, removed| {
+        histogram.push(removed.len());
+        for &(i, j) in removed {
+            depths[i][j] = Depth::Removed(round);
+        }
+    });
+    let core_size = grid.count_rolls();
+
+    PeelDepths {
+        depths,
+        histogram,
+        max_depth,
+        core_size,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Depth {
+    Empty,
+    /// Removed in the given (1-based) round
+    Removed(usize),
+    /// Never removed, part of the stable core
+    Survived,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PeelDepths {
+    depths: Vec<Vec<Depth>>,
+    /// Number of rolls removed in each round, starting from round 1
+    histogram: Vec<usize>,
+    /// Number of rounds until the grid is stable
+    max_depth: usize,
+    /// Number of rolls never removed
+    core_size: usize,
+}
+
+impl PeelDepths {
+    #[allow(dead_code)]
+    fn total_removed(&self) -> usize {
+        self.histogram.iter().sum()
+    }
+
+    #[allow(dead_code)]
+    fn print(&self) {
+        for row in &self.depths {
+            for depth in row {
+                let c = match depth {
+                    Depth::Empty => '.',
+                    Depth::Survived => '@',
+                    // Show depths as 1-9 then a-z, and '+' beyond that
+                    Depth::Removed(d) => char::from_digit(*d as u32, 36).unwrap_or('+'),
+                };
+                print!("{c}");
+            }
+            println!();
+        }
+        for (i, count) in self.histogram.iter().enumerate() {
+            println!("Round {}: {count} removed", i + 1);
+        }
+        println!("Rounds until stable: {}", self.max_depth);
+        println!("Total removed: {}", self.total_removed());
+        println!("Surviving core: {}", self.core_size);
+    }
+}
+
 fn create_grid(input: &str) -> Vec<Vec<bool>> {
     let mut grid = Vec::new();
     for line in input.lines() {
@@ -73,35 +253,285 @@ fn create_grid(input: &str) -> Vec<Vec<bool>> {
     grid
 }
 
-fn get_surrounding_cells(
-    grid: &[Vec<bool>],
-    row: usize,
-    col: usize,
-) -> impl Iterator<Item = &bool> {
-    get_surrounding_indices(grid, row, col).map(|(_, (_, b))| b)
-}
-
-fn get_surrounding_indices(
-    grid: &[Vec<bool>],
-    row: usize,
-    col: usize,
-) -> impl Iterator<Item = (usize, (usize, &bool))> {
-    let (min_row, max_row) = (
-        cmp::max(0, (row as isize) - 1) as usize,
-        cmp::min(grid.len(), row + 2),
-    );
-    let (min_col, max_col) = (
-        cmp::max(0, (col as isize) - 1) as usize,
-        cmp::min(grid[0].len(), col + 2),
-    );
-    let cur_row = row - min_row;
-    let cur_col = col - min_col;
-
-    grid[min_row..max_row]
-        .iter()
-        .enumerate()
-        .flat_map(move |(i, r)| iter::repeat(i).zip(r[min_col..max_col].iter().enumerate()))
-        .filter(move |(i, (j, _))| *i != cur_row || *j != cur_col)
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq)]
+enum Neighbourhood {
+    /// The 4 orthogonally adjacent cells
+    VonNeumann,
+    /// All 8 surrounding cells
+    Moore,
+    /// Any set of (row, col) offsets from the current cell
+    Custom(Vec<(isize, isize)>),
+}
+
+impl Neighbourhood {
+    fn offsets(&self) -> Vec<(isize, isize)> {
+        match self {
+            Self::VonNeumann => vec![(-1, 0), (0, -1), (0, 1), (1, 0)],
+            Self::Moore => (-1..=1)
+                .flat_map(|i| (-1..=1).map(move |j| (i, j)))
+                .filter(|&offset| offset != (0, 0))
+                .collect(),
+            Self::Custom(offsets) => offsets.clone(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn compare(&self, count: usize, threshold: usize) -> bool {
+        match self {
+            Self::Less => count < threshold,
+            Self::LessOrEqual => count <= threshold,
+            Self::Equal => count == threshold,
+            Self::GreaterOrEqual => count >= threshold,
+            Self::Greater => count > threshold,
+        }
+    }
+}
+
+/// Decides whether a roll can be removed, based on how many of
+/// its neighbours are also rolls. The default rule is the one
+/// from the puzzle: fewer than 4 of the 8 surrounding cells.
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    offsets: Vec<(isize, isize)>,
+    comparison: Comparison,
+    threshold: usize,
+    wrap: bool,
+}
+
+impl Rule {
+    fn new(
+        neighbourhood: Neighbourhood,
+        comparison: Comparison,
+        threshold: usize,
+        wrap: bool,
+    ) -> Rule {
+        Rule {
+            offsets: neighbourhood.offsets(),
+            comparison,
+            threshold,
+            wrap,
+        }
+    }
+
+    fn is_removable(&self, grid: &[Vec<bool>], row: usize, col: usize) -> bool {
+        let count = self.count_neighbours(grid, row, col);
+        self.comparison.compare(count, self.threshold)
+    }
+
+    fn count_neighbours(&self, grid: &[Vec<bool>], row: usize, col: usize) -> usize {
+        let (rows, cols) = (grid.len() as isize, grid[0].len() as isize);
+        let mut count = 0;
+        for &(row_off, col_off) in &self.offsets {
+            let (mut i, mut j) = (row as isize + row_off, col as isize + col_off);
+            if self.wrap {
+                // Treat the grid as a torus, so cells off one edge
+                // come back round on the opposite side
+                i = i.rem_euclid(rows);
+                j = j.rem_euclid(cols);
+            } else if i < 0 || i >= rows || j < 0 || j >= cols {
+                continue;
+            }
+            if grid[i as usize][j as usize] {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Rule::new(Neighbourhood::Moore, Comparison::Less, 4, false)
+    }
+}
+
+/// Grid of rolls packed 64 cells to a word, with every row stored
+/// in one allocation. Bit `j % 64` of word `j / 64` in a row is the
+/// cell in column `j`, and bits past the last column are always 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BitGrid {
+    rows: usize,
+    cols: usize,
+    words_per_row: usize,
+    words: Vec<u64>,
+}
+
+impl BitGrid {
+    fn row(&self, row: usize) -> &[u64] {
+        &self.words[row * self.words_per_row..(row + 1) * self.words_per_row]
+    }
+
+    fn count_rolls(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    fn remove_from_row(&mut self, row: usize, mask: &[u64]) {
+        let start = row * self.words_per_row;
+        for (word, m) in self.words[start..start + self.words_per_row]
+            .iter_mut()
+            .zip(mask)
+        {
+            *word &= !m;
+        }
+    }
+
+    /// Returns a mask of the rolls in `row` that `rule` allows to be
+    /// removed. Rather than counting neighbours cell by cell, each
+    /// neighbour offset is applied as a shift of a whole row, and the
+    /// shifted rows are summed into bit-sliced counters: `planes[k]`
+    /// holds bit `k` of every cell's neighbour count.
+    fn removable_in_row(&self, row: usize, rule: &Rule) -> Vec<u64> {
+        let max_count = cmp::max(rule.offsets.len(), rule.threshold);
+        let num_planes = cmp::max(1, (usize::BITS - max_count.leading_zeros()) as usize);
+        let mut planes = vec![vec![0u64; self.words_per_row]; num_planes];
+
+        for &(row_off, col_off) in &rule.offsets {
+            let i = row as isize + row_off;
+            let i = if rule.wrap {
+                i.rem_euclid(self.rows as isize)
+            } else if i < 0 || i >= self.rows as isize {
+                continue;
+            } else {
+                i
+            };
+            let shifted = self.shift_row(self.row(i as usize), col_off, rule.wrap);
+
+            // Ripple-carry add the shifted row into the counters
+            for (w, &bits) in shifted.iter().enumerate() {
+                let mut carry = bits;
+                for plane in planes.iter_mut() {
+                    if carry == 0 {
+                        break;
+                    }
+                    let sum = plane[w] ^ carry;
+                    carry &= plane[w];
+                    plane[w] = sum;
+                }
+            }
+        }
+
+        self.row(row)
+            .iter()
+            .enumerate()
+            .map(|(w, &rolls)| {
+                let (less, equal) = compare_planes(&planes, w, rule.threshold);
+                let matches = match rule.comparison {
+                    Comparison::Less => less,
+                    Comparison::LessOrEqual => less | equal,
+                    Comparison::Equal => equal,
+                    Comparison::GreaterOrEqual => !less,
+                    Comparison::Greater => !(less | equal),
+                };
+                matches & rolls
+            })
+            .collect()
+    }
+
+    /// Shifts a row so that bit `j` of the result is the cell in
+    /// column `j + offset`, either filling with empty cells or wrapping
+    /// round to the other side of the row.
+    fn shift_row(&self, row: &[u64], offset: isize, wrap: bool) -> Vec<u64> {
+        let mut shifted = if wrap {
+            let down = offset.rem_euclid(self.cols as isize) as usize;
+            let mut shifted = shift_down(row, down);
+            for (word, up) in shifted.iter_mut().zip(shift_up(row, self.cols - down)) {
+                *word |= up;
+            }
+            shifted
+        } else if offset >= 0 {
+            shift_down(row, offset as usize)
+        } else {
+            shift_up(row, offset.unsigned_abs())
+        };
+        // Clear anything shifted past the last column
+        if !self.cols.is_multiple_of(64)
+            && let Some(last) = shifted.last_mut()
+        {
+            *last &= (1 << (self.cols % 64)) - 1;
+        }
+        shifted
+    }
+}
+
+impl From<&[Vec<bool>]> for BitGrid {
+    fn from(grid: &[Vec<bool>]) -> Self {
+        let rows = grid.len();
+        let cols = grid.first().map_or(0, |r| r.len());
+        let words_per_row = cols.div_ceil(64);
+        let mut words = vec![0; rows * words_per_row];
+        for (i, row) in grid.iter().enumerate() {
+            for (j, _) in row.iter().enumerate().filter(|(_, b)| **b) {
+                words[i * words_per_row + j / 64] |= 1 << (j % 64);
+            }
+        }
+        BitGrid {
+            rows,
+            cols,
+            words_per_row,
+            words,
+        }
+    }
+}
+
+/// Moves every bit `n` places towards bit 0, filling with zeros
+fn shift_down(words: &[u64], n: usize) -> Vec<u64> {
+    let (word_shift, bit_shift) = (n / 64, n % 64);
+    (0..words.len())
+        .map(|i| {
+            let lo = words.get(i + word_shift).copied().unwrap_or(0);
+            let hi = words.get(i + word_shift + 1).copied().unwrap_or(0);
+            if bit_shift == 0 {
+                lo
+            } else {
+                (lo >> bit_shift) | (hi << (64 - bit_shift))
+            }
+        })
+        .collect()
+}
+
+/// Moves every bit `n` places away from bit 0, filling with zeros
+fn shift_up(words: &[u64], n: usize) -> Vec<u64> {
+    let (word_shift, bit_shift) = (n / 64, n % 64);
+    (0..words.len())
+        .map(|i| {
+            let hi = i.checked_sub(word_shift).map_or(0, |i| words[i]);
+            let lo = i.checked_sub(word_shift + 1).map_or(0, |i| words[i]);
+            if bit_shift == 0 {
+                hi
+            } else {
+                (hi << bit_shift) | (lo >> (64 - bit_shift))
+            }
+        })
+        .collect()
+}
+
+/// Compares the bit-sliced counts in word `w` of `planes` against
+/// `threshold`, returning masks of the counts that are less than
+/// and equal to it, working from the most significant bit down.
+fn compare_planes(planes: &[Vec<u64>], w: usize, threshold: usize) -> (u64, u64) {
+    let mut less = 0;
+    let mut equal = u64::MAX;
+    for (k, plane) in planes.iter().enumerate().rev() {
+        if threshold & (1 << k) != 0 {
+            less |= equal & !plane[w];
+            equal &= plane[w];
+        } else {
+            equal &= !plane[w];
+        }
+    }
+    (less, equal)
 }
 
 #[allow(dead_code)]
@@ -1,4 +1,7 @@
-use std::collections::HashSet;
+use std::{
+    cmp::{self, Reverse},
+    collections::{BTreeSet, HashMap, HashSet},
+};
 
 fn main() {
     let input = include_str!("../puzzle_input.txt");
@@ -16,33 +19,36 @@ fn main() {
 17
 32";
 
+    // print_coverage(input, 5, OutputFormat::Text);
     let result = count_all_fresh(input);
     println!("Result {result}");
 }
 
 #[allow(dead_code)]
 fn count_fresh_ingredients(input: &str) -> usize {
-    let (ranges, ingredients) = input.split_once("\n\n").expect("Failed to find blank line");
-    let ranges = find_fresh_ranges(ranges);
-
-    let mut count = 0;
-    for line in ingredients.lines() {
-        let id: usize = line.parse().expect("Failed to parse ID");
-        for range in &ranges {
-            if id >= range.start && id <= range.end {
-                count += 1;
-                break;
-            }
-        }
-    }
+    let database = parse_database(input);
+    let fresh = RangeSet::new(database.ranges);
+
+    let (count, _) = find_fresh_ids(&fresh, &database.ids);
     count
 }
 
-fn count_all_fresh(input: &str) -> usize {
-    let (ranges, _) = input.split_once("\n\n").expect("Failed to find blank line");
+/// Finds the index of the merged range each ID is in (or `None` if it
+/// isn't fresh), and how many IDs were fresh. Sorted IDs are found in
+/// one sweep, otherwise each ID is binary searched for separately.
+fn find_fresh_ids(fresh: &RangeSet, ids: &[usize]) -> (usize, Vec<Option<usize>>) {
+    let found = if ids.is_sorted() {
+        fresh.find_sorted(ids)
+    } else {
+        ids.iter().map(|&id| fresh.find(id)).collect()
+    };
+    let count = found.iter().filter(|f| f.is_some()).count();
+    (count, found)
+}
 
-    let mut ranges = find_fresh_ranges(ranges);
-    count_all_in_ranges(&mut ranges)
+fn count_all_fresh(input: &str) -> usize {
+    let database = parse_database(input);
+    RangeSet::new(database.ranges).count()
 }
 
 #[allow(dead_code)]
@@ -56,76 +62,211 @@ fn count_all_in_ranges_naive(ranges: &[Range]) -> usize {
     fresh_set.len()
 }
 
-fn count_all_in_ranges(ranges: &mut [Range]) -> usize {
-    let mut total = 0;
-    'outer: for i in 0..ranges.len() {
-        let mut range = ranges[i];
-        // adjust for overlap with previous ranges
-        for prev_range in &ranges[0..i] {
-            // whole range is overlapped
-            if range.start >= prev_range.start && range.end <= prev_range.end {
-                continue 'outer; // skip as no new IDs
-            }
-            // start is within other range, adjust start to other ranges end + 1
-            if range.start >= prev_range.start && range.start <= prev_range.end {
-                range.start = prev_range.end + 1;
+/// Parses the fresh ranges, then the IDs after the first blank line.
+/// Windows line endings, blank lines around either section, extra
+/// whitespace and `#` comments are all ignored, and the IDs can be
+/// left out entirely.
+fn parse_database(input: &str) -> Database {
+    let mut database = Database::default();
+    let mut in_ranges = true;
+    for (line_num, line) in input.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            // Only a blank line after some ranges marks the end of them
+            if !database.ranges.is_empty() {
+                in_ranges = false;
             }
-            // end is within other range, adjust end to other range's start - 1
-            else if range.end >= prev_range.start && range.end <= prev_range.end {
-                range.end = prev_range.start - 1;
+            continue;
+        }
+
+        match line.split_once('-') {
+            Some((start, end)) if in_ranges => {
+                database.ranges.push(parse_range(start, end, line_num));
             }
-            // check if current range encapsulates previous range
-            if prev_range.start >= range.start && prev_range.end <= range.end && prev_range.counted
-            {
-                total -= prev_range.count();
+            Some(_) => panic!("Line {line_num}: found range '{line}' after IDs"),
+            None => {
+                // Be lenient if the blank line between sections is missing
+                in_ranges = false;
+                let id = line
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Line {line_num}: failed to parse ID '{line}'"));
+                database.ids.push(id);
             }
         }
-        // add count of current range to total
-        total += range.count();
-        range.counted = true;
-        // Update range in ranges slice
-        ranges[i] = range;
     }
-    total
+    database
 }
 
-fn find_fresh_ranges(input: &str) -> Vec<Range> {
-    let mut ranges = Vec::new();
-    for line in input.lines() {
-        let (start, end) = line
-            .split_once('-')
-            .expect("Failed to find range separator.");
-        let (start, end) = (start.parse(), end.parse());
-        let (start, end) = (
-            start.expect("Failed to parse start"),
-            end.expect("Failed to parse end"),
-        );
-        ranges.push(Range::new(start, end));
+fn parse_range(start: &str, end: &str, line_num: usize) -> Range {
+    let parse = |s: &str| -> usize {
+        s.trim()
+            .parse()
+            .unwrap_or_else(|_| panic!("Line {line_num}: failed to parse '{s}' in range"))
+    };
+    let (start, end) = (parse(start), parse(end));
+    if start > end {
+        eprintln!("Warning: line {line_num}: range {start}-{end} is reversed, using {end}-{start}");
+        Range::new(end, start)
+    } else {
+        Range::new(start, end)
     }
-    ranges
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Database {
+    ranges: Vec<Range>,
+    ids: Vec<usize>,
 }
 
 #[allow(dead_code)]
-fn print_ranges(ranges: &[Range]) {
-    for range in ranges {
-        println!("{}-{}", range.start, range.end);
+fn print_coverage(input: &str, num_gaps: usize, format: OutputFormat) {
+    let database = parse_database(input);
+    let report = CoverageReport::new(&database.ranges, num_gaps);
+    match format {
+        OutputFormat::Text => print!("{}", report.to_text()),
+        OutputFormat::Json => println!("{}", report.to_json()),
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CoverageReport {
+    merged: RangeSet,
+    /// Largest gaps between merged ranges, biggest first
+    gaps: Vec<Range>,
+    /// Each original range, with how many IDs only it covers
+    contributions: Vec<(Range, usize)>,
+}
+
+impl CoverageReport {
+    fn new(ranges: &[Range], num_gaps: usize) -> CoverageReport {
+        let merged = RangeSet::new(ranges.iter().copied());
+
+        let mut gaps: Vec<Range> = merged
+            .ranges()
+            .windows(2)
+            .map(|w| Range::new(w[0].end + 1, w[1].start - 1))
+            .collect();
+        gaps.sort_by_key(|g| (Reverse(g.count()), g.start));
+        gaps.truncate(num_gaps);
+
+        let contributions = ranges
+            .iter()
+            .copied()
+            .zip(find_unique_contributions(ranges))
+            .collect();
+
+        CoverageReport {
+            merged,
+            gaps,
+            contributions,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = String::from("Merged ranges:\n");
+        for range in self.merged.ranges() {
+            text += &format!("{}-{}\n", range.start, range.end);
+        }
+        text += &format!("Total covered: {}\n", self.merged.count());
+        text += "Largest gaps:\n";
+        for gap in &self.gaps {
+            text += &format!("{}-{} ({} IDs)\n", gap.start, gap.end, gap.count());
+        }
+        text += "Unique contributions:\n";
+        for (range, unique) in &self.contributions {
+            text += &format!("{}-{}: {}\n", range.start, range.end, unique);
+        }
+        text
+    }
+
+    fn to_json(&self) -> String {
+        let range_json = |r: &Range| format!("{{\"start\":{},\"end\":{}}}", r.start, r.end);
+        let merged: Vec<String> = self.merged.ranges().iter().map(range_json).collect();
+        let gaps: Vec<String> = self
+            .gaps
+            .iter()
+            .map(|g| {
+                format!(
+                    "{{\"start\":{},\"end\":{},\"size\":{}}}",
+                    g.start,
+                    g.end,
+                    g.count()
+                )
+            })
+            .collect();
+        let contributions: Vec<String> = self
+            .contributions
+            .iter()
+            .map(|(r, unique)| {
+                format!(
+                    "{{\"start\":{},\"end\":{},\"unique\":{}}}",
+                    r.start, r.end, unique
+                )
+            })
+            .collect();
+        format!(
+            "{{\"merged\":[{}],\"total_covered\":{},\"largest_gaps\":[{}],\"contributions\":[{}]}}",
+            merged.join(","),
+            self.merged.count(),
+            gaps.join(","),
+            contributions.join(",")
+        )
+    }
+}
+
+/// For each range, counts the IDs no other range covers, by sweeping
+/// across every range start and end, and tracking which are active.
+fn find_unique_contributions(ranges: &[Range]) -> Vec<usize> {
+    // (position, is_start, range index), where ends are exclusive
+    let mut events = Vec::with_capacity(ranges.len() * 2);
+    for (i, range) in ranges.iter().enumerate().filter(|(_, r)| r.count() > 0) {
+        events.push((range.start, true, i));
+        // A range ending at usize::MAX never needs to end
+        if let Some(end) = range.end.checked_add(1) {
+            events.push((end, false, i));
+        }
+    }
+    events.sort_unstable();
+
+    let mut unique = vec![0; ranges.len()];
+    let mut active = BTreeSet::new();
+    let mut prev = 0;
+    for (pos, is_start, i) in events {
+        if active.len() == 1 {
+            let only = *active.first().unwrap();
+            unique[only] += pos - prev;
+        }
+        if is_start {
+            active.insert(i);
+        } else {
+            active.remove(&i);
+        }
+        prev = pos;
     }
+    if let Some(&only) = active.first()
+        && active.len() == 1
+    {
+        unique[only] += usize::MAX - prev + 1;
+    }
+    unique
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 struct Range {
     start: usize,
     end: usize,
-    counted: bool,
 }
 
 impl Range {
     fn new(start: usize, end: usize) -> Range {
-        Range {
-            start,
-            end,
-            counted: false,
-        }
+        Range { start, end }
     }
 
     fn count(&self) -> usize {
@@ -135,3 +276,350 @@ impl Range {
         self.end - self.start + 1
     }
 }
+
+/// A set of IDs stored as inclusive ranges, which are kept sorted
+/// and merged so that no two ranges overlap or are adjacent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct RangeSet {
+    ranges: Vec<Range>,
+}
+
+impl RangeSet {
+    fn new(ranges: impl IntoIterator<Item = Range>) -> RangeSet {
+        let mut ranges: Vec<Range> = ranges.into_iter().filter(|r| r.count() > 0).collect();
+        ranges.sort_unstable_by_key(|r| r.start);
+
+        let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                // Overlapping or adjacent, so extend the previous range
+                Some(last) if range.start <= last.end.saturating_add(1) => {
+                    last.end = cmp::max(last.end, range.end);
+                }
+                _ => merged.push(range),
+            }
+        }
+        RangeSet { ranges: merged }
+    }
+
+    fn ranges(&self) -> &[Range] {
+        &self.ranges
+    }
+
+    fn count(&self) -> usize {
+        self.ranges.iter().map(Range::count).sum()
+    }
+
+    /// Binary searches for the index of the range containing `id`
+    fn find(&self, id: usize) -> Option<usize> {
+        // First range that ends at or after id, which is
+        // the only one that could possibly contain it
+        let idx = self.ranges.partition_point(|r| r.end < id);
+        self.ranges.get(idx).filter(|r| r.start <= id).map(|_| idx)
+    }
+
+    #[allow(dead_code)]
+    fn contains(&self, id: usize) -> bool {
+        self.find(id).is_some()
+    }
+
+    /// Same as calling `find` for every ID, but `ids` must be sorted,
+    /// so we can walk through the IDs and ranges together instead.
+    fn find_sorted(&self, ids: &[usize]) -> Vec<Option<usize>> {
+        debug_assert!(ids.is_sorted(), "IDs must be sorted");
+        let mut idx = 0;
+        ids.iter()
+            .map(|&id| {
+                while idx < self.ranges.len() && self.ranges[idx].end < id {
+                    idx += 1;
+                }
+                self.ranges.get(idx).filter(|r| r.start <= id).map(|_| idx)
+            })
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn union(&self, other: &RangeSet) -> RangeSet {
+        RangeSet::new(self.ranges.iter().chain(&other.ranges).copied())
+    }
+
+    #[allow(dead_code)]
+    fn intersection(&self, other: &RangeSet) -> RangeSet {
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.ranges.len() && j < other.ranges.len() {
+            let (a, b) = (self.ranges[i], other.ranges[j]);
+            let (start, end) = (cmp::max(a.start, b.start), cmp::min(a.end, b.end));
+            if start <= end {
+                ranges.push(Range::new(start, end));
+            }
+            // Move past whichever range finishes first
+            if a.end < b.end {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+        // Both inputs are already disjoint, so the output is too
+        RangeSet { ranges }
+    }
+
+    #[allow(dead_code)]
+    fn difference(&self, other: &RangeSet) -> RangeSet {
+        let (Some(first), Some(last)) = (self.ranges.first(), self.ranges.last()) else {
+            return RangeSet::default();
+        };
+        self.intersection(&other.complement(Range::new(first.start, last.end)))
+    }
+
+    /// Every ID within `bounds` that isn't in the set
+    #[allow(dead_code)]
+    fn complement(&self, bounds: Range) -> RangeSet {
+        let mut ranges = Vec::new();
+        let mut next_start = bounds.start;
+        for range in &self.ranges {
+            if range.end < next_start {
+                continue;
+            }
+            if range.start > bounds.end {
+                break;
+            }
+            if range.start > next_start {
+                ranges.push(Range::new(next_start, range.start - 1));
+            }
+            match range.end.checked_add(1) {
+                Some(start) => next_start = start,
+                // Range goes right up to usize::MAX, so nothing can be after it
+                None => return RangeSet { ranges },
+            }
+        }
+        if next_start <= bounds.end {
+            ranges.push(Range::new(next_start, bounds.end));
+        }
+        RangeSet { ranges }
+    }
+}
+
+/// Fresh ranges that can be added and removed one at a time, while
+/// still quickly answering whether an ID is fresh and how many are.
+///
+/// This is a segment tree over every possible ID, with nodes only
+/// created when a range boundary falls inside them. Each node keeps
+/// how many ranges cover the whole of it, and how many IDs within it
+/// are covered, so updates and queries only need to visit the nodes
+/// down to a range's boundaries (at most 64 levels deep).
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+struct FreshIndex {
+    nodes: Vec<IndexNode>,
+    /// How many times each range has been inserted, so only
+    /// ranges that were actually inserted can be removed
+    inserted: HashMap<Range, usize>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, Default)]
+struct IndexNode {
+    /// Number of ranges covering this node's whole span
+    cover: usize,
+    /// Number of IDs in this node's span covered by any range. This
+    /// is a u128, as the root's span is one larger than usize::MAX
+    covered: u128,
+    children: Option<(usize, usize)>,
+}
+
+#[allow(dead_code)]
+impl FreshIndex {
+    fn new() -> FreshIndex {
+        FreshIndex {
+            nodes: vec![IndexNode::default()],
+            inserted: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, range: Range) {
+        if range.count() == 0 {
+            return;
+        }
+        *self.inserted.entry(range).or_insert(0) += 1;
+        self.update(0, 0, usize::MAX, range, true);
+    }
+
+    /// Removes a previously inserted range, returning false if
+    /// it wasn't in the index. IDs still covered by other ranges
+    /// stay fresh.
+    fn remove(&mut self, range: Range) -> bool {
+        match self.inserted.get_mut(&range) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                self.inserted.remove(&range);
+            }
+            None => return false,
+        }
+        self.update(0, 0, usize::MAX, range, false);
+        true
+    }
+
+    fn count(&self) -> usize {
+        usize::try_from(self.nodes[0].covered).expect("Fresh count doesn't fit in usize")
+    }
+
+    fn contains(&self, id: usize) -> bool {
+        let (mut idx, mut lo, mut hi) = (0, 0, usize::MAX);
+        loop {
+            let node = &self.nodes[idx];
+            if node.cover > 0 {
+                return true;
+            }
+            let Some((left, right)) = node.children else {
+                return false;
+            };
+            let mid = lo + (hi - lo) / 2;
+            if id <= mid {
+                (idx, hi) = (left, mid);
+            } else {
+                (idx, lo) = (right, mid + 1);
+            }
+        }
+    }
+
+    fn update(&mut self, idx: usize, lo: usize, hi: usize, range: Range, add: bool) {
+        if range.end < lo || range.start > hi {
+            return;
+        }
+        if range.start <= lo && hi <= range.end {
+            if add {
+                self.nodes[idx].cover += 1;
+            } else {
+                self.nodes[idx].cover -= 1;
+            }
+        } else {
+            let (left, right) = match self.nodes[idx].children {
+                Some(children) => children,
+                None => {
+                    let left = self.nodes.len();
+                    self.nodes.push(IndexNode::default());
+                    self.nodes.push(IndexNode::default());
+                    self.nodes[idx].children = Some((left, left + 1));
+                    (left, left + 1)
+                }
+            };
+            let mid = lo + (hi - lo) / 2;
+            self.update(left, lo, mid, range, add);
+            self.update(right, mid + 1, hi, range, add);
+        }
+
+        let node = &self.nodes[idx];
+        self.nodes[idx].covered = if node.cover > 0 {
+            (hi - lo) as u128 + 1
+        } else if let Some((left, right)) = node.children {
+            self.nodes[left].covered + self.nodes[right].covered
+        } else {
+            0
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ITERATIONS: usize = 1000;
+
+    /// xorshift, as we don't need anything better for this
+    struct Rng(u64);
+
+    impl Rng {
+        fn new() -> Rng {
+            Rng(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn next(&mut self, max: u64) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % max) as usize
+        }
+
+        fn ranges(&mut self, n: usize) -> Vec<Range> {
+            (0..n)
+                .map(|_| {
+                    let start = self.next(200);
+                    Range::new(start, start + self.next(30))
+                })
+                .collect()
+        }
+    }
+
+    fn to_hash_set(set: &RangeSet) -> HashSet<usize> {
+        set.ranges().iter().flat_map(|r| r.start..=r.end).collect()
+    }
+
+    #[test]
+    fn range_set_is_merged_and_counts_match_naive() {
+        let mut rng = Rng::new();
+        for _ in 0..ITERATIONS {
+            let ranges = rng.ranges(8);
+            let set = RangeSet::new(ranges.clone());
+            // Ranges must be sorted, and neither overlapping nor adjacent
+            assert!(set.ranges().windows(2).all(|w| w[0].end + 1 < w[1].start));
+            assert_eq!(set.count(), count_all_in_ranges_naive(&ranges));
+        }
+    }
+
+    #[test]
+    fn range_set_queries_match_brute_force() {
+        let mut rng = Rng::new();
+        let ids: Vec<usize> = (0..260).collect();
+        for _ in 0..ITERATIONS {
+            let set = RangeSet::new(rng.ranges(8));
+            let ids_in_set = to_hash_set(&set);
+            let found = set.find_sorted(&ids);
+            for &id in &ids {
+                assert_eq!(set.contains(id), ids_in_set.contains(&id));
+                assert_eq!(set.find(id), found[id]);
+            }
+        }
+    }
+
+    #[test]
+    fn range_set_operations_match_brute_force() {
+        let mut rng = Rng::new();
+        for _ in 0..ITERATIONS {
+            let (a, b) = (RangeSet::new(rng.ranges(8)), RangeSet::new(rng.ranges(8)));
+            let (set_a, set_b) = (to_hash_set(&a), to_hash_set(&b));
+            assert_eq!(to_hash_set(&a.union(&b)), &set_a | &set_b);
+            assert_eq!(to_hash_set(&a.intersection(&b)), &set_a & &set_b);
+            assert_eq!(to_hash_set(&a.difference(&b)), &set_a - &set_b);
+
+            let bounds = Range::new(rng.next(100), 100 + rng.next(150));
+            let in_bounds: HashSet<usize> = (bounds.start..=bounds.end).collect();
+            assert_eq!(to_hash_set(&a.complement(bounds)), &in_bounds - &set_a);
+        }
+    }
+
+    #[test]
+    fn fresh_index_matches_range_set_after_removals() {
+        let mut rng = Rng::new();
+        for _ in 0..ITERATIONS {
+            let (ranges_a, ranges_b) = (rng.ranges(8), rng.ranges(8));
+            let a = RangeSet::new(ranges_a.clone());
+            let both = RangeSet::new(ranges_a.iter().chain(&ranges_b).copied());
+
+            // Adding then removing b should leave the index matching a
+            let mut index = FreshIndex::new();
+            for &range in ranges_a.iter().chain(&ranges_b) {
+                index.insert(range);
+            }
+            assert_eq!(index.count(), both.count());
+            for &range in &ranges_b {
+                assert!(index.remove(range));
+            }
+            assert!(!index.remove(Range::new(1000, 1001)));
+            assert_eq!(index.count(), a.count());
+            for id in 0..260 {
+                assert_eq!(index.contains(id), a.contains(id));
+            }
+        }
+    }
+}
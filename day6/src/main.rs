@@ -1,3 +1,5 @@
+use std::{fmt, ops};
+
 fn main() {
     let input = include_str!("../puzzle_input.txt");
 
@@ -7,146 +9,531 @@ fn main() {
   6 98  215 314
 *   +   *   +  ";
 
-    // let (problem_nums, operations) = get_problems(input);
-    // let total = count_total(problem_nums, operations);
-    let total = solve_problems_two(input);
+    // let problems = parse_problems(input, ReadMode::Rows);
+    let problems = parse_problems(input, ReadMode::Columns);
+    // explain_problems(&problems);
+    // print!("{}", transpose(input));
+    let total = solve_problems(&problems);
     println!("Total = {total}");
 }
 
+/// Prints each problem written out in full, with its result and
+/// the columns it came from, followed by the grand total.
 #[allow(dead_code)]
-fn count_total(problem_nums: Vec<Vec<usize>>, operations: Vec<Operation>) -> usize {
-    let mut total = 0;
-    // We can assume all the lines have the same number of numbers and operations
-    for i in 0..problem_nums[0].len() {
-        let op = operations[i];
-        let mut cur_total = op.start_value();
+fn explain_problems(problems: &[Problem]) {
+    for problem in problems {
+        let result = match problem.solve() {
+            Ok(result) => result.to_string(),
+            Err(err) => format!("error ({err})"),
+        };
+        println!(
+            "Columns {:>3}..{:<3}  {} = {result}",
+            problem.columns.start,
+            problem.columns.end,
+            problem.expression()
+        );
+    }
+    println!("Grand total = {}", solve_problems(problems));
+}
 
-        for line in &problem_nums {
-            cur_total = op.apply(cur_total, line[i]);
+/// Sums the results of every problem, reporting (and skipping)
+/// any problem that can't be evaluated.
+fn solve_problems(problems: &[Problem]) -> usize {
+    let mut total: usize = 0;
+    for (i, problem) in problems.iter().enumerate() {
+        match problem.solve() {
+            Ok(result) => total = total.checked_add(result).expect("Grand total overflowed"),
+            Err(err) => eprintln!(
+                "Problem {} (columns {:?}) failed: {err}",
+                i + 1,
+                problem.columns
+            ),
         }
-        total += cur_total;
     }
     total
 }
 
-#[allow(dead_code)]
-fn get_problems(input: &str) -> (Vec<Vec<usize>>, Vec<Operation>) {
-    let mut problem_nums: Vec<Vec<usize>> = Vec::new();
-    let mut line_iter = input.lines().peekable();
-    while let Some(line) = line_iter.peek()
-        && line.trim_start().starts_with(|c: char| c.is_ascii_digit())
-    {
-        // We've already peeked, so this if fine
-        let line = line_iter.next().unwrap();
-        let nums = line
-            .split_whitespace()
-            .map(|num| num.parse::<usize>().expect("Failed to parse num"))
-            .collect();
-        problem_nums.push(nums);
-    }
-
-    let operations: Vec<Operation> = line_iter
-        .next()
-        .expect("No line of operations found!")
-        .split_whitespace()
-        .map(Operation::parse)
+/// Parses the worksheet into problems, using the operator line to
+/// find the columns each problem spans, then reading the numbers
+/// in those columns either row by row, or column by column.
+/// Columns are counted in chars, not bytes, and lines shorter than
+/// the others are padded with spaces, in case an editor trimmed them.
+fn parse_problems(input: &str, mode: ReadMode) -> Vec<Problem> {
+    let mut lines: Vec<Vec<char>> = input
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.chars().collect())
         .collect();
-    (problem_nums, operations)
-}
-
-fn solve_problems_two(input: &str) -> usize {
-    let last_line = input.lines().last().expect("Couldn't find last line.");
-    // Need to iterate through the line, and find the operations
-    // as well as spacing after
-    let mut ranges = Vec::new();
-    let mut ops = Vec::new();
-    let mut idx = 0;
-    while let Some(mut next_op) = last_line[idx + 1..].find(|c: char| !c.is_ascii_whitespace()) {
-        next_op += idx;
-        ranges.push(idx..next_op);
-        ops.push(Operation::parse(&last_line[idx..idx + 1]));
-        idx = next_op + 1;
-    }
-    ranges.push(idx..last_line.len());
-    ops.push(Operation::parse(&last_line[idx..idx + 1]));
-
-    // Get a vector of each vertical column of numbers, including extra spaces
-    let mut num_slices = vec![Vec::new(); ranges.len()];
-    let mut line_iter = input.lines().peekable();
-    while let Some(line) = line_iter.peek()
-        && line.trim_start().starts_with(|c: char| c.is_ascii_digit())
-    {
-        let line = line_iter.next().unwrap();
-        for i in 0..ranges.len() {
-            let range = &ranges[i];
-            num_slices[i].push(&line[range.clone()]);
-        }
-    }
-
-    let mut total = 0;
-    for i in 0..ranges.len() {
-        let op = ops[i];
-        let mut cur_total = op.start_value();
-        // print!("{cur_total}");
-        for num in get_numbers_from_slices(&num_slices[i]) {
-            cur_total = op.apply(cur_total, num);
-            // print!(" {} {}", op.char(), num)
-        }
-        // println!(" = {cur_total}");
-        total += cur_total;
+    let op_line = lines.pop().expect("No line of operations found!");
+    let width = lines
+        .iter()
+        .chain([&op_line])
+        .map(Vec::len)
+        .max()
+        .unwrap_or(0);
+    for line in &mut lines {
+        line.resize(width, ' ');
     }
-    total
+
+    find_blocks(&op_line, width)
+        .into_iter()
+        .map(|(columns, ops)| {
+            let block: Vec<&[char]> = lines.iter().map(|line| &line[columns.clone()]).collect();
+            if !block.iter().any(|row| row.iter().any(char::is_ascii_digit)) {
+                panic!("No digits found in problem at columns {columns:?}");
+            }
+            let operands = match mode {
+                ReadMode::Rows => get_numbers_from_rows(&block),
+                ReadMode::Columns => get_numbers_from_columns(&block),
+            };
+            Problem {
+                operands,
+                ops,
+                columns,
+            }
+        })
+        .collect()
+}
+
+/// Finds the columns of each problem, from the start of each
+/// operator to just before the gap preceding the next operator.
+fn find_blocks(op_line: &[char], width: usize) -> Vec<(ops::Range<usize>, Vec<Operation>)> {
+    let op_positions: Vec<usize> = (0..op_line.len())
+        .filter(|&i| !op_line[i].is_whitespace() && (i == 0 || op_line[i - 1].is_whitespace()))
+        .collect();
+
+    op_positions
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = match op_positions.get(i + 1) {
+                // Don't include the blank column separating problems
+                Some(next) => next - 1,
+                None => width,
+            };
+            let token: String = op_line[start..]
+                .iter()
+                .take_while(|c| !c.is_whitespace())
+                .collect();
+            (start..end, Operation::parse_all(&token))
+        })
+        .collect()
+}
+
+fn get_numbers_from_rows(block: &[&[char]]) -> Vec<usize> {
+    block
+        .iter()
+        .map(|row| row.iter().collect::<String>())
+        .filter(|row| !row.trim().is_empty())
+        .map(|row| {
+            row.trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("Failed to parse num '{}'", row.trim()))
+        })
+        .collect()
+}
+
+/// Reads each column of digits top to bottom as a number,
+/// working from the rightmost column to the leftmost, and
+/// skipping any columns without digits.
+fn get_numbers_from_columns(block: &[&[char]]) -> Vec<usize> {
+    let width = block.first().map_or(0, |row| row.len());
+    (0..width)
+        .rev()
+        .filter_map(|idx| {
+            let mut digits = block
+                .iter()
+                .filter_map(|row| row[idx].to_digit(10))
+                .peekable();
+            digits.peek()?;
+            Some(digits.fold(0, |num, digit| num * 10 + digit as usize))
+        })
+        .collect()
+}
+
+/// Writes problems out as a worksheet that `parse_problems` can read
+/// back in the same mode. In `Rows` layout each operand is a row of
+/// its block, and in `Columns` layout each operand is a column of
+/// digits, with the first operand in the rightmost column.
+fn render_worksheet(problems: &[Problem], mode: ReadMode) -> String {
+    let blocks: Vec<Vec<String>> = problems
+        .iter()
+        .map(|problem| match mode {
+            ReadMode::Rows => render_rows_block(problem),
+            ReadMode::Columns => render_columns_block(problem),
+        })
+        .collect();
+
+    // Pad every block to the same height, so the operators line up
+    let height = blocks.iter().map(Vec::len).max().unwrap_or(0);
+    let mut lines = vec![String::new(); height];
+    let mut op_line = String::new();
+    for (i, (problem, block)) in problems.iter().zip(&blocks).enumerate() {
+        let op_token = problem.op_token();
+        let width = block
+            .iter()
+            .map(|row| row.chars().count())
+            .chain([op_token.chars().count()])
+            .max()
+            .unwrap_or(0);
+        let separator = if i == 0 { "" } else { " " };
+        for (r, line) in lines.iter_mut().enumerate() {
+            let row = block.get(r).map_or("", String::as_str);
+            *line += &format!("{separator}{row:>width$}");
+        }
+        op_line += &format!("{separator}{op_token:<width$}");
+    }
+    lines.push(op_line);
+
+    let mut worksheet = lines.join("\n");
+    worksheet.push('\n');
+    worksheet
+}
+
+fn render_rows_block(problem: &Problem) -> Vec<String> {
+    problem.operands.iter().map(usize::to_string).collect()
+}
+
+fn render_columns_block(problem: &Problem) -> Vec<String> {
+    let digits: Vec<Vec<char>> = problem
+        .operands
+        .iter()
+        .rev()
+        .map(|num| num.to_string().chars().collect())
+        .collect();
+    let height = digits.iter().map(Vec::len).max().unwrap_or(0);
+    (0..height)
+        .map(|r| {
+            digits
+                .iter()
+                .map(|num| num.get(r).copied().unwrap_or(' '))
+                .collect()
+        })
+        .collect()
+}
+
+/// Shows how a worksheet written in `Columns` layout would look
+/// if the same problems were written in `Rows` layout instead.
+#[allow(dead_code)]
+fn transpose(input: &str) -> String {
+    render_worksheet(&parse_problems(input, ReadMode::Columns), ReadMode::Rows)
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReadMode {
+    /// Each row within a problem's columns is a number
+    Rows,
+    /// Each column is a number, read top to bottom
+    Columns,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Problem {
+    operands: Vec<usize>,
+    /// Either a single operation, applied between every operand, or
+    /// one operation between each pair of operands
+    ops: Vec<Operation>,
+    /// Columns of the worksheet this problem spans
+    columns: ops::Range<usize>,
+}
+
+impl Problem {
+    fn solve(&self) -> Result<usize, EvalError> {
+        match self.ops.as_slice() {
+            [op] if self.operands.is_empty() => op.identity().ok_or(EvalError::NoOperands),
+            [op] => evaluate_infix(&self.operands, &vec![*op; self.operands.len() - 1]),
+            ops => evaluate_infix(&self.operands, ops),
+        }
+    }
+
+    /// Writes the operations as they'd appear on the operator line
+    fn op_token(&self) -> String {
+        let symbols: Vec<&str> = self.ops.iter().map(Operation::symbol).collect();
+        if symbols.iter().all(|s| s.len() == 1) {
+            symbols.concat()
+        } else {
+            symbols.join(",")
+        }
+    }
+
+    /// Writes the problem out as `a op b op c`, with `?` for any
+    /// operator missing when there are too few for the operands
+    fn expression(&self) -> String {
+        let Some((first, rest)) = self.operands.split_first() else {
+            return String::from("(no operands)");
+        };
+        let mut expression = first.to_string();
+        for (i, num) in rest.iter().enumerate() {
+            let op = if self.ops.len() == 1 {
+                self.ops.first()
+            } else {
+                self.ops.get(i)
+            };
+            expression += &format!(" {} {num}", op.map_or("?", Operation::symbol));
+        }
+        expression
+    }
+}
+
+/// Evaluates `operands[0] ops[0] operands[1] ops[1] ...`, respecting
+/// each operation's precedence, with `^` grouping right to left and
+/// everything else grouping left to right.
+fn evaluate_infix(operands: &[usize], ops: &[Operation]) -> Result<usize, EvalError> {
+    let Some((&first, rest)) = operands.split_first() else {
+        return Err(EvalError::NoOperands);
+    };
+    if ops.len() != rest.len() {
+        return Err(EvalError::OperatorCount {
+            operands: operands.len(),
+            ops: ops.len(),
+        });
+    }
+
+    // Shunting-yard, but evaluating as we go rather than
+    // building up the output queue
+    let mut values = vec![first];
+    let mut pending: Vec<Operation> = Vec::new();
+    let apply_top = |values: &mut Vec<usize>, op: Operation| -> Result<(), EvalError> {
+        let rhs = values.pop().unwrap();
+        let lhs = values.pop().unwrap();
+        values.push(op.apply(lhs, rhs)?);
+        Ok(())
+    };
+    for (&op, &num) in ops.iter().zip(rest) {
+        while let Some(&top) = pending.last()
+            && (top.precedence() > op.precedence()
+                || (top.precedence() == op.precedence() && !op.is_right_assoc()))
+        {
+            pending.pop();
+            apply_top(&mut values, top)?;
+        }
+        pending.push(op);
+        values.push(num);
+    }
+    while let Some(top) = pending.pop() {
+        apply_top(&mut values, top)?;
+    }
+    Ok(values[0])
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum EvalError {
+    NoOperands,
+    OperatorCount {
+        operands: usize,
+        ops: usize,
+    },
+    Overflow {
+        op: Operation,
+        lhs: usize,
+        rhs: usize,
+    },
+    DivideByZero,
 }
 
-fn get_numbers_from_slices(num_slices: &[&str]) -> Vec<usize> {
-    let mut numbers = Vec::with_capacity(num_slices.len());
-    for idx in 0..num_slices[0].len() {
-        let mut cur_num = 0;
-        for slice in num_slices {
-            if let Some(c) = slice.chars().nth(idx)
-                && let Some(digit) = c.to_digit(10)
-            {
-                cur_num = cur_num * 10 + digit;
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoOperands => write!(f, "no operands, and operation has no identity"),
+            Self::OperatorCount { operands, ops } => {
+                write!(f, "{ops} operations can't go between {operands} operands")
             }
+            Self::Overflow { op, lhs, rhs } => {
+                write!(f, "{lhs} {} {rhs} overflowed", op.symbol())
+            }
+            Self::DivideByZero => write!(f, "divide by zero"),
         }
-        numbers.push(cur_num as usize);
     }
-    numbers
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum Operation {
     Add,
+    Sub,
     Mul,
+    Div,
+    Rem,
+    Pow,
+    Max,
+    Min,
 }
 
 impl Operation {
     fn parse(op: &str) -> Operation {
         match op {
             "+" => Self::Add,
+            "-" => Self::Sub,
             "*" => Self::Mul,
+            "/" => Self::Div,
+            "%" => Self::Rem,
+            "^" => Self::Pow,
+            "max" => Self::Max,
+            "min" => Self::Min,
             huh => panic!("Couldn't parse {huh} to Operation"),
         }
     }
 
-    fn start_value(&self) -> usize {
+    /// Parses either a single operation, or several to go between
+    /// operands, written together (`+*-`) or separated by commas
+    /// (`+,max,*`).
+    fn parse_all(token: &str) -> Vec<Operation> {
+        if token.contains(',') {
+            token.split(',').map(Self::parse).collect()
+        } else if token.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            vec![Self::parse(token)]
+        } else {
+            token
+                .char_indices()
+                .map(|(i, c)| Self::parse(&token[i..i + c.len_utf8()]))
+                .collect()
+        }
+    }
+
+    /// Result of applying the operation to no operands at all, for
+    /// those operations where that makes sense
+    fn identity(&self) -> Option<usize> {
         match self {
-            Self::Add => 0,
-            Self::Mul => 1,
+            Self::Add | Self::Max => Some(0),
+            Self::Mul => Some(1),
+            Self::Sub | Self::Div | Self::Rem | Self::Pow | Self::Min => None,
         }
     }
 
-    fn apply(&self, val1: usize, val2: usize) -> usize {
+    fn apply(&self, lhs: usize, rhs: usize) -> Result<usize, EvalError> {
+        let result = match self {
+            Self::Add => lhs.checked_add(rhs),
+            Self::Sub => lhs.checked_sub(rhs),
+            Self::Mul => lhs.checked_mul(rhs),
+            Self::Div | Self::Rem if rhs == 0 => return Err(EvalError::DivideByZero),
+            Self::Div => Some(lhs / rhs),
+            Self::Rem => Some(lhs % rhs),
+            Self::Pow => u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_pow(rhs)),
+            Self::Max => Some(lhs.max(rhs)),
+            Self::Min => Some(lhs.min(rhs)),
+        };
+        result.ok_or(EvalError::Overflow {
+            op: *self,
+            lhs,
+            rhs,
+        })
+    }
+
+    fn precedence(&self) -> u8 {
         match self {
-            Self::Add => val1 + val2,
-            Self::Mul => val1 * val2,
+            Self::Max | Self::Min => 0,
+            Self::Add | Self::Sub => 1,
+            Self::Mul | Self::Div | Self::Rem => 2,
+            Self::Pow => 3,
         }
     }
 
-    fn char(&self) -> char {
+    fn is_right_assoc(&self) -> bool {
+        *self == Self::Pow
+    }
+
+    fn symbol(&self) -> &'static str {
         match self {
-            Self::Add => '+',
-            Self::Mul => '*',
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::Pow => "^",
+            Self::Max => "max",
+            Self::Min => "min",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_INPUT: &str = "123 328  51 64 \n 45 64  387 23 \n  6 98  215 314\n*   +   *   +  ";
+
+    fn strip(problems: &[Problem]) -> Vec<(Vec<usize>, Vec<Operation>)> {
+        problems
+            .iter()
+            .map(|p| (p.operands.clone(), p.ops.clone()))
+            .collect()
+    }
+
+    /// Renders the problems in both layouts, and checks re-parsing
+    /// each gives back the same problems
+    fn assert_round_trip(problems: &[Problem]) {
+        for mode in [ReadMode::Rows, ReadMode::Columns] {
+            let worksheet = render_worksheet(problems, mode);
+            let reparsed = parse_problems(&worksheet, mode);
+            assert_eq!(
+                strip(problems),
+                strip(&reparsed),
+                "Round trip failed rendering {mode:?}:\n{worksheet}"
+            );
+        }
+    }
+
+    fn problem(operands: &[usize], ops: &[Operation]) -> Problem {
+        Problem {
+            operands: operands.to_vec(),
+            ops: ops.to_vec(),
+            columns: 0..0,
+        }
+    }
+
+    #[test]
+    fn test_input_round_trips() {
+        for mode in [ReadMode::Rows, ReadMode::Columns] {
+            assert_round_trip(&parse_problems(TEST_INPUT, mode));
+        }
+    }
+
+    #[test]
+    fn rendered_problems_round_trip() {
+        use Operation::*;
+        assert_round_trip(&[problem(&[1, 22, 333], &[Add])]);
+        assert_round_trip(&[
+            problem(&[12, 3, 100, 4], &[Add, Max, Mul]),
+            problem(&[5, 60], &[Pow]),
+            problem(&[7, 8, 9], &[Sub, Div]),
+        ]);
+        assert_round_trip(&[
+            problem(&[1000, 1], &[Min]),
+            problem(&[9], &[Rem]),
+            problem(&[45, 678, 9, 10], &[Mul, Add, Sub]),
+        ]);
+    }
+
+    #[test]
+    fn multi_op_tokens_parse() {
+        use Operation::*;
+        let input = "   12 5\n    3 6\n  100 7\n+,max *";
+        let problems = parse_problems(input, ReadMode::Rows);
+        assert_eq!(
+            strip(&problems),
+            vec![
+                (vec![12, 3, 100], vec![Add, Max]),
+                (vec![5, 6, 7], vec![Mul])
+            ]
+        );
+        assert_round_trip(&problems);
+
+        let ops = Operation::parse_all("+,max,*");
+        assert_eq!(ops, vec![Add, Max, Mul]);
+        assert_eq!(Operation::parse_all("+*-"), vec![Add, Mul, Sub]);
+    }
+
+    #[test]
+    fn ragged_lines_parse_like_padded_ones() {
+        let ragged: String = TEST_INPUT
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n");
+        for mode in [ReadMode::Rows, ReadMode::Columns] {
+            let problems = parse_problems(&ragged, mode);
+            assert_eq!(strip(&problems), strip(&parse_problems(TEST_INPUT, mode)));
+            assert_round_trip(&problems);
         }
     }
 }
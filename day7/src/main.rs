@@ -1,3 +1,5 @@
+use std::{cmp, collections::HashSet, fmt};
+
 fn main() {
     let input = include_str!("../puzzle_input.txt");
 
@@ -21,53 +23,625 @@ fn main() {
 
     // let split_count = count_splits(input);
     // println!("Split count: {split_count}");
-    let paths_count = count_pathways(input);
+    // let paths_count: BigUint = count_pathways(input);
+    // let paths_count: ModPrime<1_000_000_007> = count_pathways(input);
+    // print!("{}", render_manifold(input, OutputFormat::Ascii, true));
+    // count_pathways_by_source::<BigUint>(input).print();
+    let paths_count: u128 = count_pathways(input);
     println!("Number of paths: {paths_count}");
 }
 
 #[allow(dead_code)]
 fn count_splits(input: &str) -> usize {
-    let mut total = 0;
-    let mut input_lines = input.lines().peekable();
-    let first_line = input_lines.peek().expect("No lines!");
-    let mut beams = vec![false; first_line.len()];
-    let beam_index = first_line.find('S').expect("No 'S' on first line");
-    beams[beam_index] = true;
-
-    // We can skip every other line, and start on 3rd line
-    for line in input_lines.step_by(2).skip(1) {
-        for (i, c) in line.chars().enumerate() {
-            // If an existing beam hits a splitter...
-            if c == '^' && beams[i] {
-                // ...remove beam at splitter, add one either side, increment total
-                beams[i] = false;
-                beams[i - 1] = true;
-                beams[i + 1] = true;
-                total += 1;
+    Manifold::from(input).simulate().split_count()
+}
+
+fn count_pathways<T: PathCount>(input: &str) -> T {
+    Manifold::from(input).simulate().path_count()
+}
+
+#[allow(dead_code)]
+fn count_pathways_by_source<T: PathCount>(input: &str) -> PathMatrix<T> {
+    Manifold::from(input).simulate().paths_by_source()
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Tile {
+    Empty,
+    Source,
+    /// `^`, splits a beam to the columns either side
+    Splitter,
+    /// `*`, splits a beam to the columns either side and carries on straight
+    TripleSplitter,
+    /// `/`, moves a beam one column left
+    LeftDeflector,
+    /// `\`, moves a beam one column right
+    RightDeflector,
+    /// `#`, stops any beam hitting it
+    Absorber,
+    /// `v`, pulls beams passing either side of it into its own column
+    Funnel,
+}
+
+impl Tile {
+    fn parse(c: char) -> Tile {
+        match c {
+            '.' => Self::Empty,
+            'S' => Self::Source,
+            '^' => Self::Splitter,
+            '*' => Self::TripleSplitter,
+            '/' => Self::LeftDeflector,
+            '\\' => Self::RightDeflector,
+            '#' => Self::Absorber,
+            'v' => Self::Funnel,
+            wat => panic!("Failed to parse tile '{wat}'"),
+        }
+    }
+
+    fn char(&self) -> char {
+        match self {
+            Self::Empty => '.',
+            Self::Source => 'S',
+            Self::Splitter => '^',
+            Self::TripleSplitter => '*',
+            Self::LeftDeflector => '/',
+            Self::RightDeflector => '\\',
+            Self::Absorber => '#',
+            Self::Funnel => 'v',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Manifold {
+    /// Every row is padded with empty tiles to the same width
+    tiles: Vec<Vec<Tile>>,
+    width: usize,
+}
+
+impl From<&str> for Manifold {
+    fn from(value: &str) -> Self {
+        let mut tiles: Vec<Vec<Tile>> = value
+            .lines()
+            .map(|line| line.chars().map(Tile::parse).collect())
+            .collect();
+        let width = tiles.iter().map(Vec::len).max().unwrap_or(0);
+        for row in &mut tiles {
+            row.resize(width, Tile::Empty);
+        }
+        Manifold { tiles, width }
+    }
+}
+
+impl Manifold {
+    /// Sends beams down from every source, a row at a time, and builds
+    /// up the graph of which splitters each beam goes on to hit. A beam
+    /// hitting a splitter, deflector or funnel carries on from the row
+    /// below, in whichever column(s) the tile sends it to, and beams
+    /// sent off the edge of the manifold exit there. Splitters no beam
+    /// reaches aren't in the graph at all.
+    fn simulate(&self) -> BeamGraph {
+        let mut graph = BeamGraph::default();
+        // The nodes each column's beam (if any) has come from. A node
+        // appears more than once if its beams have rejoined since, as
+        // each is a different path.
+        let mut beams: Vec<Vec<usize>> = vec![Vec::new(); self.width];
+
+        for (row, tiles) in self.tiles.iter().enumerate() {
+            graph
+                .lit
+                .push(beams.iter().map(|p| !p.is_empty()).collect());
+            let mut next_beams = vec![Vec::new(); self.width];
+            let mut send = |graph: &mut BeamGraph, parents: &[usize], col: isize| {
+                if col < 0 {
+                    graph.add_node(Node::Exit(Exit::Left { row }), parents);
+                } else if col as usize >= self.width {
+                    graph.add_node(Node::Exit(Exit::Right { row }), parents);
+                } else {
+                    next_beams[col as usize].extend_from_slice(parents);
+                }
+            };
+
+            for (col, tile) in tiles.iter().enumerate() {
+                if *tile == Tile::Source {
+                    let node = graph.add_node(Node::Source { row, col }, &[]);
+                    send(&mut graph, &[node], col as isize);
+                }
+                let parents = &beams[col];
+                if parents.is_empty() {
+                    continue;
+                }
+                let col = col as isize;
+                match tile {
+                    Tile::Splitter | Tile::TripleSplitter => {
+                        let node = graph.add_node(
+                            Node::Splitter {
+                                row,
+                                col: col as usize,
+                            },
+                            parents,
+                        );
+                        send(&mut graph, &[node], col - 1);
+                        if *tile == Tile::TripleSplitter {
+                            send(&mut graph, &[node], col);
+                        }
+                        send(&mut graph, &[node], col + 1);
+                    }
+                    Tile::LeftDeflector => send(&mut graph, parents, col - 1),
+                    Tile::RightDeflector => send(&mut graph, parents, col + 1),
+                    Tile::Absorber => {
+                        graph.add_node(
+                            Node::Absorber {
+                                row,
+                                col: col as usize,
+                            },
+                            parents,
+                        );
+                    }
+                    Tile::Empty | Tile::Source | Tile::Funnel => {
+                        // Empty tiles next to a funnel get pulled into it,
+                        // preferring the left funnel if there's one each side
+                        let funnel = [col - 1, col + 1].into_iter().find(|&c| {
+                            usize::try_from(c).is_ok_and(|c| tiles.get(c) == Some(&Tile::Funnel))
+                        });
+                        match funnel {
+                            Some(funnel) if *tile == Tile::Empty => {
+                                send(&mut graph, parents, funnel)
+                            }
+                            _ => send(&mut graph, parents, col),
+                        }
+                    }
+                }
+            }
+            beams = next_beams;
+        }
+
+        for (col, parents) in beams.iter().enumerate() {
+            if !parents.is_empty() {
+                graph.add_node(Node::Exit(Exit::Bottom { col }), parents);
+            }
+        }
+        graph
+    }
+
+    /// Draws the manifold with `|` wherever a beam travelled, and
+    /// any splitters a beam never reached replaced with `x`. If
+    /// `show_paths` is set, the number of paths leaving the bottom of
+    /// each column is written underneath, top to bottom.
+    fn render_ascii(&self, graph: &BeamGraph, show_paths: bool) -> String {
+        let hit = graph.splitters_hit();
+        let mut text = String::new();
+        for (row, tiles) in self.tiles.iter().enumerate() {
+            for (col, tile) in tiles.iter().enumerate() {
+                let c = match tile {
+                    Tile::Empty if graph.lit[row][col] => '|',
+                    Tile::Splitter | Tile::TripleSplitter if !hit.contains(&(row, col)) => 'x',
+                    tile => tile.char(),
+                };
+                text.push(c);
+            }
+            text.push('\n');
+        }
+
+        if show_paths {
+            let counts: Vec<String> = graph
+                .bottom_exit_paths(self.width)
+                .iter()
+                .map(|paths| match paths {
+                    Some(paths) => paths.to_string(),
+                    None => String::new(),
+                })
+                .collect();
+            let height = counts.iter().map(String::len).max().unwrap_or(0);
+            for i in 0..height {
+                text.push('\n');
+                for count in &counts {
+                    text.push(count.chars().nth(i).unwrap_or(' '));
+                }
+            }
+            if height > 0 {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Same as `render_ascii`, but as an SVG image, with splitters
+    /// that were hit in red, and those never reached in grey.
+    fn render_svg(&self, graph: &BeamGraph, show_paths: bool) -> String {
+        const CELL: usize = 12;
+        let hit = graph.splitters_hit();
+        let exit_paths: Vec<Option<String>> = if show_paths {
+            graph
+                .bottom_exit_paths(self.width)
+                .iter()
+                .map(|paths| paths.as_ref().map(|p| p.to_string()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        // The path counts are written downwards, so leave room below
+        // the manifold for the longest, plus a cell of margin
+        let footer = match exit_paths.iter().flatten().map(String::len).max() {
+            Some(max_len) => (max_len + 1) * CELL,
+            None => 0,
+        };
+        let (width, height) = (self.width * CELL, self.tiles.len() * CELL + footer);
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+             font-family=\"monospace\" font-size=\"{}\">\n",
+            CELL - 2
+        );
+        svg += &format!("<rect width=\"{width}\" height=\"{height}\" fill=\"black\"/>\n");
+
+        for (row, tiles) in self.tiles.iter().enumerate() {
+            for (col, tile) in tiles.iter().enumerate() {
+                let (x, y) = (col * CELL, row * CELL);
+                let (cx, cy) = (x + CELL / 2, y + CELL / 2);
+                if graph.lit[row][col] {
+                    svg += &format!(
+                        "<line x1=\"{cx}\" y1=\"{y}\" x2=\"{cx}\" y2=\"{}\" stroke=\"cyan\"/>\n",
+                        y + CELL
+                    );
+                }
+                let colour = match tile {
+                    Tile::Empty => continue,
+                    Tile::Splitter | Tile::TripleSplitter if hit.contains(&(row, col)) => "red",
+                    Tile::Splitter | Tile::TripleSplitter => "grey",
+                    Tile::Source => "yellow",
+                    _ => "white",
+                };
+                svg += &format!(
+                    "<text x=\"{cx}\" y=\"{}\" fill=\"{colour}\" text-anchor=\"middle\">{}</text>\n",
+                    cy + CELL / 3,
+                    xml_escape(tile.char())
+                );
+            }
+        }
+
+        let y = self.tiles.len() * CELL + CELL / 2;
+        for (col, paths) in exit_paths.iter().enumerate() {
+            if let Some(paths) = paths {
+                let x = col * CELL + CELL / 2;
+                svg += &format!(
+                    "<text x=\"{x}\" y=\"{y}\" fill=\"white\" \
+                     transform=\"rotate(90 {x} {y})\">{paths}</text>\n"
+                );
+            }
+        }
+        svg += "</svg>\n";
+        svg
+    }
+}
+
+fn xml_escape(c: char) -> String {
+    match c {
+        '&' => String::from("&amp;"),
+        '<' => String::from("&lt;"),
+        '>' => String::from("&gt;"),
+        c => c.to_string(),
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OutputFormat {
+    Ascii,
+    Svg,
+}
+
+#[allow(dead_code)]
+fn render_manifold(input: &str, format: OutputFormat, show_paths: bool) -> String {
+    let manifold = Manifold::from(input);
+    let graph = manifold.simulate();
+    match format {
+        OutputFormat::Ascii => manifold.render_ascii(&graph, show_paths),
+        OutputFormat::Svg => manifold.render_svg(&graph, show_paths),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Node {
+    Source { row: usize, col: usize },
+    Splitter { row: usize, col: usize },
+    Absorber { row: usize, col: usize },
+    Exit(Exit),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Exit {
+    Bottom { col: usize },
+    Left { row: usize },
+    Right { row: usize },
+}
+
+/// Directed acyclic graph of the sources, the splitters the beams
+/// hit, and where the beams leave the manifold. Nodes are only ever
+/// added after all their parents, so index order is a topological
+/// order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct BeamGraph {
+    nodes: Vec<Node>,
+    children: Vec<Vec<usize>>,
+    /// Which tiles of the manifold a beam arrived at
+    lit: Vec<Vec<bool>>,
+}
+
+impl BeamGraph {
+    fn add_node(&mut self, node: Node, parents: &[usize]) -> usize {
+        let idx = self.nodes.len();
+        self.nodes.push(node);
+        self.children.push(Vec::new());
+        for &parent in parents {
+            self.children[parent].push(idx);
+        }
+        idx
+    }
+
+    fn split_count(&self) -> usize {
+        self.nodes
+            .iter()
+            .filter(|n| matches!(n, Node::Splitter { .. }))
+            .count()
+    }
+
+    /// Number of different paths from any source to each node
+    fn paths_to_nodes<T: PathCount>(&self) -> Vec<T> {
+        self.paths_from(|node| matches!(node, Node::Source { .. }))
+    }
+
+    /// Number of different paths to each node, starting from
+    /// any of the nodes `is_start` picks
+    fn paths_from<T: PathCount>(&self, is_start: impl Fn(&Node) -> bool) -> Vec<T> {
+        let mut paths = vec![T::zero(); self.nodes.len()];
+        for (i, node) in self.nodes.iter().enumerate() {
+            if is_start(node) {
+                paths[i] = paths[i].add(&T::one());
+            }
+            let from = paths[i].clone();
+            for &child in &self.children[i] {
+                paths[child] = paths[child].add(&from);
+            }
+        }
+        paths
+    }
+
+    /// Number of paths from each source to each exit
+    fn paths_by_source<T: PathCount>(&self) -> PathMatrix<T> {
+        let exits: Vec<(usize, Exit)> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, n)| match n {
+                Node::Exit(exit) => Some((i, *exit)),
+                _ => None,
+            })
+            .collect();
+
+        let mut sources = Vec::new();
+        let mut paths = Vec::new();
+        for source in &self.nodes {
+            if let Node::Source { row, col } = *source {
+                let to_nodes = self.paths_from::<T>(|node| node == source);
+                paths.push(exits.iter().map(|(i, _)| to_nodes[*i].clone()).collect());
+                sources.push((row, col));
+            }
+        }
+        PathMatrix {
+            sources,
+            exits: exits.into_iter().map(|(_, exit)| exit).collect(),
+            paths,
+        }
+    }
+
+    fn splitters_hit(&self) -> HashSet<(usize, usize)> {
+        self.nodes
+            .iter()
+            .filter_map(|n| match n {
+                Node::Splitter { row, col } => Some((*row, *col)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Number of paths leaving the bottom of each column, if any
+    fn bottom_exit_paths(&self, width: usize) -> Vec<Option<BigUint>> {
+        let mut exits = vec![None; width];
+        for (paths, node) in self
+            .paths_to_nodes::<BigUint>()
+            .into_iter()
+            .zip(&self.nodes)
+        {
+            if let Node::Exit(Exit::Bottom { col }) = node {
+                exits[*col] = Some(paths);
+            }
+        }
+        exits
+    }
+
+    /// Number of different paths from any source to any exit
+    fn path_count<T: PathCount>(&self) -> T {
+        self.paths_to_nodes::<T>()
+            .iter()
+            .zip(&self.nodes)
+            .filter(|(_, n)| matches!(n, Node::Exit(_)))
+            .fold(T::zero(), |total, (paths, _)| total.add(paths))
+    }
+}
+
+/// Paths from every source to every exit, where `paths[s][e]` is
+/// the number of paths from `sources[s]` to `exits[e]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathMatrix<T> {
+    sources: Vec<(usize, usize)>,
+    exits: Vec<Exit>,
+    paths: Vec<Vec<T>>,
+}
+
+#[allow(dead_code)]
+impl<T: PathCount> PathMatrix<T> {
+    /// Total paths from each source, to any exit
+    fn source_totals(&self) -> Vec<T> {
+        self.paths
+            .iter()
+            .map(|row| row.iter().fold(T::zero(), |total, paths| total.add(paths)))
+            .collect()
+    }
+
+    fn print(&self) {
+        let exit_names: Vec<String> = self
+            .exits
+            .iter()
+            .map(|exit| match exit {
+                Exit::Bottom { col } => format!("col {col}"),
+                Exit::Left { row } => format!("left {row}"),
+                Exit::Right { row } => format!("right {row}"),
+            })
+            .collect();
+        let cells: Vec<Vec<String>> = self
+            .paths
+            .iter()
+            .map(|row| row.iter().map(T::to_string).collect())
+            .collect();
+        let widths: Vec<usize> = exit_names
+            .iter()
+            .enumerate()
+            .map(|(e, name)| {
+                cells
+                    .iter()
+                    .map(|row| row[e].len())
+                    .chain([name.len()])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        print!("{:16}", "");
+        for (name, width) in exit_names.iter().zip(&widths) {
+            print!(" {name:>width$}");
+        }
+        println!("  total");
+        for ((source, row), total) in self.sources.iter().zip(&cells).zip(self.source_totals()) {
+            print!("{:16}", format!("S at ({}, {})", source.0, source.1));
+            for (cell, width) in row.iter().zip(&widths) {
+                print!(" {cell:>width$}");
             }
+            println!("  {total}");
         }
     }
-    total
 }
 
-fn count_pathways(input: &str) -> usize {
-    let mut input_lines = input.lines();
-    let first_line = input_lines.next().expect("No lines!");
-    let mut paths = vec![0; first_line.len()];
-    let beam_idx = first_line.find('S').expect("No 'S' on first line");
-    paths[beam_idx] = 1;
+/// Number type to count paths in. The number of paths can double at
+/// every splitter, so deep manifolds need either a big enough number
+/// type, or to settle for counting modulo some number.
+trait PathCount: Clone + fmt::Display {
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn add(&self, other: &Self) -> Self;
+}
+
+impl PathCount for u128 {
+    fn zero() -> Self {
+        0
+    }
+
+    fn one() -> Self {
+        1
+    }
 
-    // We can skip 2nd line, and skip every alternate line
-    for line in input_lines.skip(1).step_by(2) {
-        for (i, c) in line.chars().enumerate() {
-            // If an existing beam hits a splitter...
-            if c == '^' && paths[i] > 0 {
-                // ...add number of paths to either side, and set num paths to 0
-                paths[i - 1] += paths[i];
-                paths[i + 1] += paths[i];
-                paths[i] = 0;
+    fn add(&self, other: &Self) -> Self {
+        self.checked_add(*other)
+            .expect("Path count overflowed u128, count with BigUint instead")
+    }
+}
+
+/// Unsigned integer with as many 64 bit limbs as it needs,
+/// least significant first. Only supports what counting needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BigUint {
+    limbs: Vec<u64>,
+}
+
+impl PathCount for BigUint {
+    fn zero() -> Self {
+        BigUint { limbs: Vec::new() }
+    }
+
+    fn one() -> Self {
+        BigUint { limbs: vec![1] }
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        let len = cmp::max(self.limbs.len(), other.limbs.len());
+        let mut limbs = Vec::with_capacity(len + 1);
+        let mut carry = false;
+        for i in 0..len {
+            let a = self.limbs.get(i).copied().unwrap_or(0);
+            let b = other.limbs.get(i).copied().unwrap_or(0);
+            let (sum, carry1) = a.overflowing_add(b);
+            let (sum, carry2) = sum.overflowing_add(carry as u64);
+            limbs.push(sum);
+            carry = carry1 || carry2;
+        }
+        if carry {
+            limbs.push(1);
+        }
+        BigUint { limbs }
+    }
+}
+
+impl fmt::Display for BigUint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Repeatedly divide by the largest power of 10 fitting in a
+        // u64, to get the decimal digits 19 at a time
+        const CHUNK: u64 = 10_000_000_000_000_000_000;
+        let mut limbs = self.limbs.clone();
+        let mut chunks = Vec::new();
+        while limbs.iter().any(|&l| l != 0) {
+            let mut rem: u128 = 0;
+            for limb in limbs.iter_mut().rev() {
+                let cur = (rem << 64) | *limb as u128;
+                *limb = (cur / CHUNK as u128) as u64;
+                rem = cur % CHUNK as u128;
+            }
+            chunks.push(rem as u64);
+        }
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((most_significant, rest)) => {
+                write!(f, "{most_significant}")?;
+                for chunk in rest.iter().rev() {
+                    write!(f, "{chunk:019}")?;
+                }
+                Ok(())
             }
         }
     }
-    paths.iter().sum()
+}
+
+/// Path count modulo `P`, which would usually be a large prime
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ModPrime<const P: u64>(u64);
+
+impl<const P: u64> PathCount for ModPrime<P> {
+    fn zero() -> Self {
+        const { assert!(P > 1, "Modulus must be greater than 1") };
+        ModPrime(0)
+    }
+
+    fn one() -> Self {
+        const { assert!(P > 1, "Modulus must be greater than 1") };
+        ModPrime(1)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        ModPrime(((self.0 as u128 + other.0 as u128) % P as u128) as u64)
+    }
+}
+
+impl<const P: u64> fmt::Display for ModPrime<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (mod {P})", self.0)
+    }
 }
@@ -1,6 +1,7 @@
 use std::{
     cmp::Reverse,
-    collections::{HashMap, HashSet},
+    collections::{BinaryHeap, HashMap},
+    fmt::Display,
 };
 
 fn main() {
@@ -28,203 +29,929 @@ fn main() {
 984,92,344
 425,690,689";
 
-    // part_one(input);
-    part_two(input);
+    // part_one(input, Metric::Euclidean);
+    // print_circuit_report(input, Metric::Euclidean, 1000, OutputFormat::Text);
+    // print_spanning_tree(input, Metric::Euclidean, MstAlgorithm::Prim);
+    // report_ties(input, Metric::Euclidean, 1000);
+    // print_clusters(input, Metric::Euclidean, Cut::Count(5));
+    // print_dendrogram(input, Metric::Euclidean);
+    part_two(input, Metric::Euclidean);
 }
 
 #[allow(dead_code)]
-fn part_one(input: &str) {
+fn part_one(input: &str, metric: Metric) {
     let positions = get_positions(input);
     let num_closest_connections = 1000;
-    let closest_pairs = find_n_closest_pairs(&positions, num_closest_connections);
+    let closest_pairs = find_n_closest_pairs(&positions, metric, num_closest_connections);
 
-    let circuits = group_by_closest(&closest_pairs);
-    for take_five in circuits.iter().take(5) {
-        println!("Circuit size: {}", take_five.len());
+    let mut circuits = UnionFind::new(positions.len());
+    for pair in &closest_pairs {
+        circuits.union(pair.1, pair.2);
     }
-    let mult = circuits
-        .iter()
-        .take(3)
-        .fold(1, |i, circuit| i * circuit.len());
+    let sizes = circuits.component_sizes();
+    for size in sizes.iter().take(5) {
+        println!("Circuit size: {size}");
+    }
+    let mult: usize = sizes.iter().take(3).product();
     println!(
         "Top 3 multiplied: {} * {} * {} = {}",
-        circuits[0].len(),
-        circuits[1].len(),
-        circuits[2].len(),
-        mult
+        sizes[0], sizes[1], sizes[2], mult
     );
 }
 
-fn part_two(input: &str) {
+fn part_two(input: &str, metric: Metric) {
     let positions = get_positions(input);
     eprintln!("Num positions: {}", positions.len());
-    // use 0 in find_n_closest_pairs to get all pairs
-    let pairs_by_distance = find_n_closest_pairs(&positions, 0);
-    eprintln!("Num pairs: {}", pairs_by_distance.len());
-    let last_pair = group_all(&pairs_by_distance, positions.len());
+    // The last connection needed is the last edge Kruskal's
+    // algorithm adds to the minimum spanning tree
+    let tree = minimum_spanning_tree(&positions, metric, MstAlgorithm::Kruskal);
+    let (_, a, b) = *tree.edges.last().expect("Need at least 2 junctions");
+    let last_pair = (a, b);
     println!(
-        "Last pair: {:?} & {:?}",
+        "Last pair: {} & {}",
         positions[last_pair.0], positions[last_pair.1]
     );
-    let (x1, x2) = (positions[last_pair.0].x, positions[last_pair.1].x);
-    println!("{} * {} = {}", x1, x2, x1 * x2);
+    let (x1, x2) = (
+        positions[last_pair.0].coord(0),
+        positions[last_pair.1].coord(0),
+    );
+    println!("{} * {} = {}", x1, x2, x1 as i128 * x2 as i128);
+}
+
+#[allow(dead_code)]
+fn print_circuit_report(input: &str, metric: Metric, num_connections: usize, format: OutputFormat) {
+    let positions = get_positions(input);
+    let report = CircuitReport::new(&positions, metric, num_connections);
+    match format {
+        OutputFormat::Text => print!("{}", report.to_text()),
+        OutputFormat::Json => println!("{}", report.to_json()),
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// State of the circuits after connecting the closest pairs
+#[derive(Debug, Clone, PartialEq)]
+struct CircuitReport {
+    num_connections: usize,
+    /// (circuit size, number of circuits that size), largest first
+    size_counts: Vec<(usize, usize)>,
+    num_circuits: usize,
+    num_singletons: usize,
+    /// Junctions in the largest circuit
+    largest: Vec<usize>,
+    /// Every connection that joined two circuits, in order
+    merges: Vec<Merge>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Merge {
+    /// Which connection this was, counting from 1
+    connection: usize,
+    a: usize,
+    b: usize,
+    length: f64,
+    /// Size of the circuit the merge made
+    size: usize,
+}
+
+impl CircuitReport {
+    fn new(positions: &[Position], metric: Metric, num_connections: usize) -> CircuitReport {
+        let mut circuits = UnionFind::new(positions.len());
+        let mut merges = Vec::new();
+        let pairs = ClosestPairs::new(positions, metric).take(num_connections);
+        for (i, (distance, a, b)) in pairs.enumerate() {
+            if circuits.union(a, b) {
+                merges.push(Merge {
+                    connection: i + 1,
+                    a,
+                    b,
+                    length: metric.length(distance),
+                    size: circuits.component_size(a),
+                });
+            }
+        }
+
+        let mut size_counts: Vec<(usize, usize)> = Vec::new();
+        for size in circuits.component_sizes() {
+            match size_counts.last_mut() {
+                Some((last, count)) if *last == size => *count += 1,
+                _ => size_counts.push((size, 1)),
+            }
+        }
+        let num_singletons = size_counts
+            .iter()
+            .find(|(size, _)| *size == 1)
+            .map_or(0, |(_, count)| *count);
+        let largest = circuits.components().into_iter().next().unwrap_or_default();
+
+        CircuitReport {
+            num_connections,
+            size_counts,
+            num_circuits: circuits.num_components(),
+            num_singletons,
+            largest,
+            merges,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("After {} connections:\n", self.num_connections);
+        text += &format!("Circuits: {}\n", self.num_circuits);
+        text += &format!("Singletons: {}\n", self.num_singletons);
+        text += "Circuit sizes:\n";
+        for (size, count) in &self.size_counts {
+            text += &format!("{size}: {count}\n");
+        }
+        text += &format!("Largest circuit: {:?}\n", self.largest);
+        text += "Merges:\n";
+        for m in &self.merges {
+            text += &format!(
+                "#{}: {} -- {} ({}), size {}\n",
+                m.connection, m.a, m.b, m.length, m.size
+            );
+        }
+        text
+    }
+
+    fn to_json(&self) -> String {
+        let size_counts: Vec<String> = self
+            .size_counts
+            .iter()
+            .map(|(size, count)| format!("{{\"size\":{size},\"count\":{count}}}"))
+            .collect();
+        let largest: Vec<String> = self.largest.iter().map(|j| j.to_string()).collect();
+        let merges: Vec<String> = self
+            .merges
+            .iter()
+            .map(|m| {
+                format!(
+                    "{{\"connection\":{},\"a\":{},\"b\":{},\"length\":{},\"size\":{}}}",
+                    m.connection, m.a, m.b, m.length, m.size
+                )
+            })
+            .collect();
+        format!(
+            "{{\"connections\":{},\"circuits\":{},\"singletons\":{},\"size_counts\":[{}],\"largest\":[{}],\"merges\":[{}]}}",
+            self.num_connections,
+            self.num_circuits,
+            self.num_singletons,
+            size_counts.join(","),
+            largest.join(","),
+            merges.join(",")
+        )
+    }
+}
+
+#[allow(dead_code)]
+fn print_spanning_tree(input: &str, metric: Metric, algorithm: MstAlgorithm) {
+    let positions = get_positions(input);
+    let tree = minimum_spanning_tree(&positions, metric, algorithm);
+    print!("{}", tree.to_csv());
+    println!("Total length: {}", tree.total_length());
+    if let Some((distance, a, b)) = tree.longest_edge() {
+        println!("Longest edge: {a} -- {b} ({})", metric.length(distance));
+    }
+}
+
+#[allow(dead_code)]
+fn print_clusters(input: &str, metric: Metric, cut: Cut) {
+    let positions = get_positions(input);
+    let dendrogram = Dendrogram::new(&positions, metric);
+    let clusters = dendrogram.cut(cut);
+    println!("{} clusters", clusters.len());
+    for cluster in clusters {
+        println!("{} junctions: {cluster:?}", cluster.len());
+    }
+}
+
+#[allow(dead_code)]
+fn print_dendrogram(input: &str, metric: Metric) {
+    let positions = get_positions(input);
+    println!("{}", Dendrogram::new(&positions, metric).to_newick());
+}
+
+/// Where to cut a dendrogram into clusters
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Cut {
+    /// Keep merges no longer than this distance
+    Distance(f64),
+    /// Keep merging until there are this many clusters
+    Count(usize),
+}
+
+/// Full merge hierarchy from single-linkage clustering, which is what
+/// connecting the closest pairs does. Clusters are numbered with the
+/// junctions first, then each merge makes the next cluster, so merge
+/// `i` makes cluster `num_junctions + i`.
+#[derive(Debug, Clone, PartialEq)]
+struct Dendrogram {
+    num_junctions: usize,
+    /// Merges in order, shortest distance first
+    merges: Vec<ClusterMerge>,
+    metric: Metric,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct ClusterMerge {
+    left: usize,
+    right: usize,
+    distance: i128,
+    /// Number of junctions in the merged cluster
+    size: usize,
+}
+
+impl Dendrogram {
+    /// The merges are the minimum spanning tree's edges in the order
+    /// Kruskal's algorithm adds them, tracking which cluster each
+    /// circuit's root junction currently belongs to.
+    fn new(positions: &[Position], metric: Metric) -> Dendrogram {
+        let num_junctions = positions.len();
+        let tree = minimum_spanning_tree(positions, metric, MstAlgorithm::Kruskal);
+        let mut circuits = UnionFind::new(num_junctions);
+        let mut cluster_of: Vec<usize> = (0..num_junctions).collect();
+        let mut merges = Vec::with_capacity(tree.edges.len());
+        for &(distance, a, b) in &tree.edges {
+            let (left, right) = (cluster_of[circuits.find(a)], cluster_of[circuits.find(b)]);
+            circuits.union(a, b);
+            let root = circuits.find(a);
+            cluster_of[root] = num_junctions + merges.len();
+            merges.push(ClusterMerge {
+                left: left.min(right),
+                right: left.max(right),
+                distance,
+                size: circuits.component_size(root),
+            });
+        }
+        Dendrogram {
+            num_junctions,
+            merges,
+            metric,
+        }
+    }
+
+    /// Junctions in each cluster, largest cluster first
+    fn cut(&self, cut: Cut) -> Vec<Vec<usize>> {
+        let num_merges = match cut {
+            Cut::Distance(threshold) => self
+                .merges
+                .partition_point(|m| self.metric.length(m.distance) <= threshold),
+            Cut::Count(count) => self
+                .num_junctions
+                .saturating_sub(count.max(1))
+                .min(self.merges.len()),
+        };
+        self.clusters_after(num_merges)
+    }
+
+    fn clusters_after(&self, num_merges: usize) -> Vec<Vec<usize>> {
+        let mut circuits = UnionFind::new(self.num_junctions);
+        // Any junction in a cluster will do to merge it with another
+        let mut member: Vec<usize> = (0..self.num_junctions).collect();
+        for merge in &self.merges[..num_merges] {
+            circuits.union(member[merge.left], member[merge.right]);
+            member.push(member[merge.left]);
+        }
+        circuits.components()
+    }
+
+    /// Newick tree with junction indices as leaf names, and branch
+    /// lengths the distance between a cluster's merge and its parent's.
+    /// Single linkage tends to chain, so the tree can be as deep as
+    /// there are junctions, and is walked with a stack, not recursion.
+    fn to_newick(&self) -> String {
+        enum Step {
+            Cluster(usize),
+            Text(String),
+        }
+
+        if self.num_junctions == 0 {
+            return String::from(";");
+        }
+        let mut newick = String::new();
+        let mut stack = vec![Step::Cluster(self.num_junctions + self.merges.len() - 1)];
+        while let Some(step) = stack.pop() {
+            let cluster = match step {
+                Step::Text(text) => {
+                    newick += &text;
+                    continue;
+                }
+                Step::Cluster(cluster) => cluster,
+            };
+            let Some(merge) = cluster.checked_sub(self.num_junctions) else {
+                newick += &cluster.to_string();
+                continue;
+            };
+            let merge = self.merges[merge];
+            let height = self.height(cluster);
+            newick += "(";
+            // Pushed in reverse, so they come off the stack in order
+            stack.push(Step::Text(format!(
+                ":{})",
+                height - self.height(merge.right)
+            )));
+            stack.push(Step::Cluster(merge.right));
+            stack.push(Step::Text(format!(
+                ":{},",
+                height - self.height(merge.left)
+            )));
+            stack.push(Step::Cluster(merge.left));
+        }
+        newick + ";"
+    }
+
+    fn height(&self, cluster: usize) -> f64 {
+        match cluster.checked_sub(self.num_junctions) {
+            Some(merge) => self.metric.length(self.merges[merge].distance),
+            None => 0.0,
+        }
+    }
+}
+
+/// Reports whether ties in distance could have changed either answer.
+/// Ties are always broken by junction index, so the answers are
+/// deterministic, but if pairs the same distance apart straddle the
+/// part one cutoff, or another pair the same length as the last
+/// connection would also have joined the final two circuits, a
+/// different tie-break could have given a different answer.
+#[allow(dead_code)]
+fn report_ties(input: &str, metric: Metric, num_connections: usize) {
+    let positions = get_positions(input);
+
+    let mut pairs = ClosestPairs::new(&positions, metric);
+    let included: Vec<(i128, usize, usize)> = pairs.by_ref().take(num_connections).collect();
+    if let Some(&(cutoff, _, _)) = included.last() {
+        let tied_in = included.iter().filter(|p| p.0 == cutoff).count();
+        let tied_out = pairs.take_while(|p| p.0 == cutoff).count();
+        if tied_out > 0 {
+            println!(
+                "Part one: {tied_in} included and {tied_out} excluded pairs tie at cutoff distance {}",
+                metric.length(cutoff)
+            );
+        } else {
+            println!("Part one: no ties at the cutoff");
+        }
+    }
+
+    let tree = minimum_spanning_tree(&positions, metric, MstAlgorithm::Kruskal);
+    let Some(&(last, a, b)) = tree.edges.last() else {
+        return;
+    };
+    // Circuits just before the last connection
+    let mut circuits = UnionFind::new(positions.len());
+    for &(_, x, y) in &tree.edges[..tree.edges.len() - 1] {
+        circuits.union(x, y);
+    }
+    let rivals: Vec<(usize, usize)> = ClosestPairs::new(&positions, metric)
+        .take_while(|p| p.0 <= last)
+        .filter(|&(d, x, y)| d == last && (x, y) != (a, b) && circuits.find(x) != circuits.find(y))
+        .map(|(_, x, y)| (x, y))
+        .collect();
+    if rivals.is_empty() {
+        println!("Part two: no ties for the last connection");
+    } else {
+        println!(
+            "Part two: last connection {a} -- {b} ties with {} other pair(s) joining the final circuits: {rivals:?}",
+            rivals.len()
+        );
+    }
 }
 
-fn find_n_closest_pairs(positions: &[Position], n: usize) -> Vec<(f64, usize, usize)> {
+/// Finds the `n` closest pairs, closest first, or every pair if `n`
+/// is 0. For `n` > 0, the pairs are streamed from the k-d tree, so
+/// each junction only looks at as many neighbours as it needs to.
+fn find_n_closest_pairs(
+    positions: &[Position],
+    metric: Metric,
+    n: usize,
+) -> Vec<(i128, usize, usize)> {
+    if n == 0 {
+        return find_all_pairs(positions, metric);
+    }
+    ClosestPairs::new(positions, metric).take(n).collect()
+}
+
+fn find_all_pairs(positions: &[Position], metric: Metric) -> Vec<(i128, usize, usize)> {
     let len = positions.len();
     let mut pair_distances = Vec::with_capacity((len * (len - 1)) / 2);
     for i in 0..len {
         for j in i + 1..len {
-            let distance = positions[i].distance_from(&positions[j]);
+            let distance = metric.distance(&positions[i], &positions[j]);
             pair_distances.push((distance, i, j));
         }
     }
-    pair_distances.sort_by(|a, b| a.0.total_cmp(&b.0));
-    // pass n = 0 to return all distances
-    if n != 0 {
-        pair_distances.truncate(n);
-        eprintln!("Truncated to {}", n);
-    }
+    // Sort by index as well as distance, so ties always come out the same
+    pair_distances.sort_unstable();
     pair_distances
 }
 
+/// Parses one position per line, panicking if they don't all
+/// have the same number of dimensions
 fn get_positions(input: &str) -> Vec<Position> {
-    input.lines().map(Position::from).collect()
-}
-
-// This approach goes from one connection to the next, grouping,
-// until there are no more connections, to avoid having to join
-// 2 existing circuits together at any point. Unfortunately, we
-// can't use this approach for part 2, since we need to join all
-// junctions into one big circuit, and see which is the last
-// connection to be made before they're all linked.
-fn group_by_closest(closest_pairs: &[(f64, usize, usize)]) -> Vec<HashSet<usize>> {
-    let mut circuits = Vec::new();
-    let mut used = HashSet::new();
-    let mut connection_count = 0;
-    'outer: while let Some(start_pair) = closest_pairs.iter().find(|p| !used.contains(&p.1)) {
-        let mut circuit = HashSet::new();
-        circuit.insert(start_pair.1);
-        circuit.insert(start_pair.2);
-        used.insert(start_pair.1);
-        used.insert(start_pair.2);
-        connection_count += 1;
-        if connection_count >= closest_pairs.len() {
-            break;
+    let positions: Vec<Position> = input.lines().map(Position::from).collect();
+    if let Some(first) = positions.first() {
+        let dims = first.dims();
+        if let Some(pos) = positions.iter().find(|p| p.dims() != dims) {
+            panic!("Expected {dims} coordinates, found {pos:?}");
         }
-        loop {
-            let mut added = 0;
-            for pair in closest_pairs {
-                if circuit.contains(&pair.1) && !circuit.contains(&pair.2) {
-                    circuit.insert(pair.2);
-                    used.insert(pair.2);
-                    added += 1;
-                    connection_count += 1;
-                } else if circuit.contains(&pair.2) && !circuit.contains(&pair.1) {
-                    circuit.insert(pair.1);
-                    used.insert(pair.1);
-                    added += 1;
-                    connection_count += 1;
-                }
-                if connection_count >= closest_pairs.len() {
-                    break 'outer;
-                }
-            }
-            if added == 0 {
-                break;
-            }
+    }
+    positions
+}
+
+/// How the distance between two junctions is measured. Distances are
+/// integers so they compare exactly, with Euclidean distances kept
+/// squared, which orders pairs the same as the distances themselves.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Metric {
+    Euclidean,
+    /// Sum of the differences along each axis
+    Manhattan,
+    /// Largest difference along any one axis
+    Chebyshev,
+}
+
+impl Metric {
+    /// Panics if the distance doesn't fit in an i128, which can only
+    /// happen with coordinates near the limits of an i64
+    fn distance(&self, a: &Position, b: &Position) -> i128 {
+        let mut diffs = a
+            .coords
+            .iter()
+            .zip(&b.coords)
+            .map(|(&a, &b)| (a as i128 - b as i128).abs());
+        match self {
+            Metric::Euclidean => diffs.try_fold(0i128, |sum, d| {
+                d.checked_mul(d).and_then(|sq| sum.checked_add(sq))
+            }),
+            Metric::Manhattan => diffs.try_fold(0i128, |sum, d| sum.checked_add(d)),
+            Metric::Chebyshev => diffs.max().or(Some(0)),
+        }
+        .unwrap_or_else(|| panic!("Distance from {a} to {b} overflowed"))
+    }
+
+    /// Smallest distance two junctions `diff` apart along one axis
+    /// can be, for pruning the k-d tree search
+    fn axis_bound(&self, diff: i128) -> i128 {
+        match self {
+            Metric::Euclidean => diff.saturating_mul(diff),
+            Metric::Manhattan | Metric::Chebyshev => diff.abs(),
+        }
+    }
+
+    /// Actual length of a distance returned by `distance`
+    fn length(&self, distance: i128) -> f64 {
+        match self {
+            Metric::Euclidean => (distance as f64).sqrt(),
+            Metric::Manhattan | Metric::Chebyshev => distance as f64,
         }
-        circuits.push(circuit);
     }
-    circuits.sort_by_key(|c| Reverse(c.len()));
-    circuits
 }
 
-fn group_all(closest_pairs: &[(f64, usize, usize)], num_junctions: usize) -> (usize, usize) {
-    let mut sets = Vec::new();
-    let mut idx_map = HashMap::new();
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum MstAlgorithm {
+    /// Connects the closest pairs that aren't already in the same
+    /// circuit, using the k-d tree to find them
+    Kruskal,
+    /// Grows one circuit by repeatedly adding the nearest junction
+    /// to it. Takes O(n²) time, but only O(n) memory.
+    Prim,
+}
+
+fn minimum_spanning_tree(
+    positions: &[Position],
+    metric: Metric,
+    algorithm: MstAlgorithm,
+) -> SpanningTree {
+    let edges = match algorithm {
+        MstAlgorithm::Kruskal => kruskal(ClosestPairs::new(positions, metric), positions.len()),
+        MstAlgorithm::Prim => prim(positions, metric),
+    };
+    SpanningTree { edges, metric }
+}
+
+/// Connects pairs in order, skipping any already in the same circuit,
+/// until every junction is in one circuit. Pairs are expected closest
+/// first, so the tree's edges are in the order they're connected.
+fn kruskal(
+    closest_pairs: impl IntoIterator<Item = (i128, usize, usize)>,
+    num_junctions: usize,
+) -> Vec<(i128, usize, usize)> {
+    let mut edges = Vec::with_capacity(num_junctions.saturating_sub(1));
+    let mut circuits = UnionFind::new(num_junctions);
+    if circuits.num_components() <= 1 {
+        return edges;
+    }
     for pair in closest_pairs {
-        match (idx_map.get(&pair.1), idx_map.get(&pair.2)) {
-            (None, None) => {
-                let mut new_map = HashSet::new();
-                new_map.insert(pair.1);
-                new_map.insert(pair.2);
-                idx_map.insert(pair.1, sets.len());
-                idx_map.insert(pair.2, sets.len());
-                sets.push(Some(new_map));
+        if circuits.union(pair.1, pair.2) {
+            edges.push(pair);
+            if circuits.num_components() == 1 {
+                return edges;
             }
-            (Some(&idx1), None) => {
-                sets[idx1].as_mut().unwrap().insert(pair.2);
-                idx_map.insert(pair.2, idx1);
-                if sets[idx1].as_ref().unwrap().len() == num_junctions {
-                    return (pair.1, pair.2);
-                }
+        }
+    }
+    // Should hopefully never reach here, as after iterating
+    // through all connections, we should have one circuit
+    panic!("Failed to merge all junctions into one circuit");
+}
+
+/// Grows the tree from junction 0, tracking the distance from each
+/// junction not yet in the tree to its nearest junction in the tree.
+/// Edges are sorted by length afterwards, to match Kruskal's order.
+fn prim(positions: &[Position], metric: Metric) -> Vec<(i128, usize, usize)> {
+    let len = positions.len();
+    let mut edges = Vec::with_capacity(len.saturating_sub(1));
+    let mut in_tree = vec![false; len];
+    // (distance, nearest junction in tree) for each junction
+    let mut nearest = vec![(i128::MAX, 0); len];
+    let mut current = 0;
+    for _ in 1..len {
+        in_tree[current] = true;
+        let mut next = None;
+        for j in (0..len).filter(|&j| !in_tree[j]) {
+            let distance = metric.distance(&positions[current], &positions[j]);
+            if (distance, current) < nearest[j] {
+                nearest[j] = (distance, current);
             }
-            (None, Some(&idx2)) => {
-                sets[idx2].as_mut().unwrap().insert(pair.1);
-                idx_map.insert(pair.1, idx2);
-                if sets[idx2].as_ref().unwrap().len() == num_junctions {
-                    return (pair.1, pair.2);
-                }
+            if next.is_none_or(|n: usize| nearest[j] < nearest[n]) {
+                next = Some(j);
             }
-            (Some(idx1), Some(idx2)) if idx1 == idx2 => {
-                // Do nothing, already in same circuit
+        }
+        let next = next.expect("Ran out of junctions before tree was complete");
+        let (distance, from) = nearest[next];
+        edges.push((distance, from.min(next), from.max(next)));
+        current = next;
+    }
+    edges.sort_unstable();
+    edges
+}
+
+/// Tree connecting every junction with the least total cable,
+/// with edges shortest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpanningTree {
+    /// (distance, junction, junction) of each edge
+    edges: Vec<(i128, usize, usize)>,
+    metric: Metric,
+}
+
+#[allow(dead_code)]
+impl SpanningTree {
+    fn total_length(&self) -> f64 {
+        self.edges.iter().map(|e| self.metric.length(e.0)).sum()
+    }
+
+    fn longest_edge(&self) -> Option<(i128, usize, usize)> {
+        self.edges.last().copied()
+    }
+
+    fn to_dot(&self, positions: &[Position]) -> String {
+        let mut dot = String::from("graph mst {\n");
+        for (i, pos) in positions.iter().enumerate() {
+            dot += &format!("    {i} [label=\"{pos}\"];\n");
+        }
+        for &(distance, a, b) in &self.edges {
+            let distance = self.metric.length(distance);
+            dot += &format!("    {a} -- {b} [label=\"{distance:.2}\"];\n");
+        }
+        dot += "}\n";
+        dot
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("a,b,distance,length\n");
+        for &(distance, a, b) in &self.edges {
+            let length = self.metric.length(distance);
+            csv += &format!("{a},{b},{distance},{length}\n");
+        }
+        csv
+    }
+}
+
+/// Disjoint-set forest tracking which circuit each junction is in,
+/// using path compression and union by size, so finding a junction's
+/// circuit and merging circuits are both close to constant time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UnionFind {
+    parent: Vec<usize>,
+    /// Size of each circuit, only kept up to date for root junctions
+    size: Vec<usize>,
+    num_components: usize,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> UnionFind {
+        UnionFind {
+            parent: (0..len).collect(),
+            size: vec![1; len],
+            num_components: len,
+        }
+    }
+
+    /// Finds the root junction of `junction`'s circuit
+    fn find(&mut self, mut junction: usize) -> usize {
+        while self.parent[junction] != junction {
+            // Point at grandparent as we go, halving the path each time
+            self.parent[junction] = self.parent[self.parent[junction]];
+            junction = self.parent[junction];
+        }
+        junction
+    }
+
+    /// Merges the circuits containing `a` and `b`, returning
+    /// false if they were already in the same circuit
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        if self.size[a] < self.size[b] {
+            (a, b) = (b, a);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+        self.num_components -= 1;
+        true
+    }
+
+    fn num_components(&self) -> usize {
+        self.num_components
+    }
+
+    fn component_size(&mut self, junction: usize) -> usize {
+        let root = self.find(junction);
+        self.size[root]
+    }
+
+    /// Sizes of every circuit, largest first
+    fn component_sizes(&self) -> Vec<usize> {
+        let mut sizes: Vec<usize> = (0..self.parent.len())
+            .filter(|&j| self.parent[j] == j)
+            .map(|root| self.size[root])
+            .collect();
+        sizes.sort_by_key(|&size| Reverse(size));
+        sizes
+    }
+
+    /// Junctions in each circuit, largest circuit first
+    fn components(&mut self) -> Vec<Vec<usize>> {
+        let mut by_root: HashMap<usize, Vec<usize>> = HashMap::new();
+        for junction in 0..self.parent.len() {
+            let root = self.find(junction);
+            by_root.entry(root).or_default().push(junction);
+        }
+        let mut components: Vec<Vec<usize>> = by_root.into_values().collect();
+        components.sort_by_key(|c| (Reverse(c.len()), c[0]));
+        components
+    }
+}
+
+/// Pair of junctions and the distance between them, ordered
+/// by distance, then by junction indices to break ties
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Pair {
+    distance: i128,
+    a: usize,
+    b: usize,
+}
+
+/// k-d tree over junction positions, stored as junction indices
+/// arranged so the median of each slice splits it on one axis, with
+/// the axis cycling through each dimension at each level down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KdTree {
+    order: Vec<usize>,
+    dims: usize,
+    metric: Metric,
+}
+
+impl KdTree {
+    fn new(positions: &[Position], metric: Metric) -> KdTree {
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        let dims = positions.first().map_or(1, |p| p.dims().max(1));
+        Self::build(&mut order, positions, dims, 0);
+        KdTree {
+            order,
+            dims,
+            metric,
+        }
+    }
+
+    fn build(order: &mut [usize], positions: &[Position], dims: usize, depth: usize) {
+        if order.len() <= 1 {
+            return;
+        }
+        let (mid, axis) = (order.len() / 2, depth % dims);
+        order.select_nth_unstable_by_key(mid, |&j| positions[j].coord(axis));
+        let (left, right) = order.split_at_mut(mid);
+        Self::build(left, positions, dims, depth + 1);
+        Self::build(&mut right[1..], positions, dims, depth + 1);
+    }
+
+    /// Finds the `k` nearest junctions to `junction`, nearest first
+    fn nearest(&self, positions: &[Position], junction: usize, k: usize) -> Vec<Pair> {
+        let mut found = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            self.search(&self.order, 0, positions, junction, k, &mut found);
+        }
+        found.into_sorted_vec()
+    }
+
+    fn search(
+        &self,
+        order: &[usize],
+        depth: usize,
+        positions: &[Position],
+        junction: usize,
+        k: usize,
+        found: &mut BinaryHeap<Pair>,
+    ) {
+        if order.is_empty() {
+            return;
+        }
+        let (mid, axis) = (order.len() / 2, depth % self.dims);
+        let other = order[mid];
+        if other != junction {
+            found.push(Pair {
+                distance: self
+                    .metric
+                    .distance(&positions[junction], &positions[other]),
+                a: junction,
+                b: other,
+            });
+            if found.len() > k {
+                found.pop();
             }
-            (Some(&idx1), Some(&idx2)) => {
-                // Check if lengths of both sets sum to num of junctions,
-                // if so, this will be the final merge to add all juctions
-                // to one circuit, so just break early and return the pair
-                let (len1, len2) = (
-                    sets[idx1].as_ref().unwrap().len(),
-                    sets[idx2].as_ref().unwrap().len(),
-                );
-                if len1 + len2 == num_junctions {
-                    return (pair.1, pair.2);
-                }
-                // Now we need to merge sets, and make sure idx_map is updated
-                let (set_add, set_drop, idx_add);
-                if len1 >= len2 {
-                    set_drop = sets[idx2].take().unwrap();
-                    set_add = sets[idx1].as_mut().unwrap();
-                    idx_add = idx1;
-                } else {
-                    set_drop = sets[idx1].take().unwrap();
-                    set_add = sets[idx2].as_mut().unwrap();
-                    idx_add = idx2;
-                }
-                for junc in set_drop {
-                    set_add.insert(junc);
-                    idx_map.insert(junc, idx_add);
-                }
-                if set_add.len() == num_junctions {}
+        }
+
+        let diff = positions[junction].coord(axis) as i128 - positions[other].coord(axis) as i128;
+        let (near, far) = if diff < 0 {
+            (&order[..mid], &order[mid + 1..])
+        } else {
+            (&order[mid + 1..], &order[..mid])
+        };
+        self.search(near, depth + 1, positions, junction, k, found);
+        // Only look on the far side if something there could be closer
+        // than the furthest we've found so far
+        let bound = self.metric.axis_bound(diff);
+        if found.len() < k || found.peek().is_some_and(|p| bound <= p.distance) {
+            self.search(far, depth + 1, positions, junction, k, found);
+        }
+    }
+}
+
+/// Iterator over every pair of junctions, closest first, working them
+/// out as they're needed. Each junction has a list of its nearest
+/// neighbours from the k-d tree, doubled in length whenever it runs
+/// out, and a heap holds the next unused neighbour of every junction.
+struct ClosestPairs<'a> {
+    positions: &'a [Position],
+    tree: KdTree,
+    neighbours: Vec<Vec<Pair>>,
+    next: Vec<usize>,
+    heap: BinaryHeap<Reverse<Pair>>,
+}
+
+impl<'a> ClosestPairs<'a> {
+    const INITIAL_NEIGHBOURS: usize = 8;
+
+    fn new(positions: &'a [Position], metric: Metric) -> ClosestPairs<'a> {
+        let tree = KdTree::new(positions, metric);
+        let neighbours: Vec<Vec<Pair>> = (0..positions.len())
+            .map(|i| tree.nearest(positions, i, Self::INITIAL_NEIGHBOURS))
+            .collect();
+        let heap = neighbours
+            .iter()
+            .filter_map(|n| n.first().copied().map(Reverse))
+            .collect();
+        ClosestPairs {
+            positions,
+            tree,
+            next: vec![0; positions.len()],
+            neighbours,
+            heap,
+        }
+    }
+}
+
+impl Iterator for ClosestPairs<'_> {
+    type Item = (i128, usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let Reverse(pair) = self.heap.pop()?;
+            let i = pair.a;
+            self.next[i] += 1;
+            let found = self.neighbours[i].len();
+            if self.next[i] == found && found < self.positions.len() - 1 {
+                self.neighbours[i] = self.tree.nearest(self.positions, i, found * 2);
+            }
+            if let Some(&next_pair) = self.neighbours[i].get(self.next[i]) {
+                self.heap.push(Reverse(next_pair));
+            }
+            // Each pair comes up once from each end, only return it once
+            if pair.a < pair.b {
+                return Some((pair.distance, pair.a, pair.b));
             }
         }
     }
-    // Should hopefully never reach here, as after iterating
-    // through all connections, we should have one circuit
-    panic!("Failed to merge all junctions into one circuit");
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// Junction position, with any number of integer coordinates. Being
+/// integers, distances between them can be compared exactly, with no
+/// rounding to make different distances look the same or vice versa.
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct Position {
-    x: f64,
-    y: f64,
-    z: f64,
+    coords: Vec<i64>,
 }
 
 impl Position {
-    fn distance_from(&self, other: &Self) -> f64 {
-        let (x_diff, y_diff, z_diff) = (self.x - other.x, self.y - other.y, self.z - other.z);
-        let (x_diff, y_diff, z_diff) = (x_diff * x_diff, y_diff * y_diff, z_diff * z_diff);
-        (x_diff + y_diff + z_diff).sqrt()
+    fn dims(&self) -> usize {
+        self.coords.len()
+    }
+
+    fn coord(&self, axis: usize) -> i64 {
+        self.coords[axis]
     }
 }
 
 impl From<&str> for Position {
     fn from(value: &str) -> Self {
-        let split: Vec<&str> = value.split(',').collect();
-        let (x, y, z) = (split[0], split[1], split[2]);
-        let (x, y, z) = (x.parse().unwrap(), y.parse().unwrap(), z.parse().unwrap());
-        Position { x, y, z }
+        let coords = value
+            .split(',')
+            .map(|s| s.trim().parse().expect("Failed to parse coordinate"))
+            .collect();
+        Position { coords }
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let coords: Vec<String> = self.coords.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}", coords.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift, as we don't need anything better for this
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, max: u64) -> i64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % max) as i64
+        }
+
+        /// Small coordinates, so plenty of pairs tie on distance
+        fn positions(&mut self, len: usize, dims: usize) -> Vec<Position> {
+            (0..len)
+                .map(|_| Position {
+                    coords: (0..dims).map(|_| self.next(40) - 20).collect(),
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "overflowed")]
+    fn euclidean_distance_overflow_panics() {
+        let (a, b) = (
+            Position::from("-9000000000000000000,0,0"),
+            Position::from("9000000000000000000,0,0"),
+        );
+        Metric::Euclidean.distance(&a, &b);
+    }
+
+    #[test]
+    fn kd_tree_pairs_match_all_pairs() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for metric in [Metric::Euclidean, Metric::Manhattan, Metric::Chebyshev] {
+            for dims in 1..=4 {
+                let positions = rng.positions(300, dims);
+                let all_pairs = find_all_pairs(&positions, metric);
+                let streamed: Vec<(i128, usize, usize)> =
+                    ClosestPairs::new(&positions, metric).collect();
+                assert_eq!(streamed, all_pairs, "{metric:?} in {dims}D");
+                for n in [1, 10, 1000] {
+                    assert_eq!(
+                        find_n_closest_pairs(&positions, metric, n),
+                        all_pairs[..n],
+                        "{n} closest, {metric:?} in {dims}D"
+                    );
+                }
+            }
+        }
     }
 }